@@ -1,16 +1,31 @@
+from typing import List
 from fastapi import FastAPI, UploadFile, File
 from wdtagger import Tagger
 from PIL import Image
 import PIL.Image
 import io
+import os
+
+MODEL_REPO = os.getenv("TAGGER_MODEL", "SmilingWolf/wd-swinv2-tagger-v3")
 
 app = FastAPI()
-tagger = Tagger()
+tagger = Tagger(model_repo=MODEL_REPO)
 PIL.Image.MAX_IMAGE_PIXELS = 106606278
+MAX_BATCH_SIZE = 32
+SUPPORTED_EXTENSIONS = ('.png', '.jpg', '.jpeg', '.webp')
+
+@app.get("/health")
+async def health():
+    # The model is loaded at import time, so answering at all means it is ready.
+    return {"status": "ok"}
+
+@app.get("/info")
+async def info():
+    return {"batch": True, "max_batch_size": MAX_BATCH_SIZE, "model": MODEL_REPO}
 
 @app.post("/tag/")
 async def tag(file: UploadFile = File(...)):
-    if not file.filename.lower().endswith(('.png', '.jpg', '.jpeg', '.webp')):
+    if not file.filename.lower().endswith(SUPPORTED_EXTENSIONS):
         return {"error": "Unsupported file type. Please upload an image."}
     try:
         image = Image.open(io.BytesIO(await file.read()))
@@ -19,6 +34,29 @@ async def tag(file: UploadFile = File(...)):
     except Exception as e:
         return {"error": str(e)}
 
+@app.post("/tag/batch/")
+async def tag_batch(files: List[UploadFile] = File(...)):
+    results = [None] * len(files)
+    images = []
+    indices = []
+    for index, file in enumerate(files):
+        if not file.filename.lower().endswith(SUPPORTED_EXTENSIONS):
+            results[index] = {"error": "Unsupported file type. Please upload an image."}
+            continue
+        try:
+            images.append(Image.open(io.BytesIO(await file.read())))
+            indices.append(index)
+        except Exception as e:
+            results[index] = {"error": str(e)}
+    if images:
+        try:
+            for index, tags in zip(indices, tagger.tag(images)):
+                results[index] = TagData.from_tags(tags)
+        except Exception as e:
+            for index in indices:
+                results[index] = {"error": str(e)}
+    return results
+
 class TagData:
     def __init__(self, character_tags, general_tags, rating):
         self.character_tags = character_tags
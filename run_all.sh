@@ -15,9 +15,10 @@ python main.py &
 
 /usr/local/bin/tag_api &
 
-sleep 30s
-
 /usr/local/bin/tag_manager &
 
+# Bash doesn't pass signals on to background jobs, so forward them and wait for
+# everything to exit, which lets tag_manager finish in-flight imports.
+trap 'kill -TERM $(jobs -p) 2>/dev/null; wait' TERM INT
 
 wait
@@ -0,0 +1,52 @@
+//! Color conversion shared by tag_manager, which stores palettes in CIELAB, and
+//! tag_api, which converts search colors the same way to compare them.
+
+/// sRGB (D65) to CIELAB.
+pub fn srgb_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|x| {
+        let c = x as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.072175 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 0.05, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn black_and_white() {
+        assert_close(srgb_to_lab([0, 0, 0]), [0.0, 0.0, 0.0]);
+        assert_close(srgb_to_lab([255, 255, 255]), [100.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn primaries() {
+        assert_close(srgb_to_lab([255, 0, 0]), [53.24, 80.09, 67.20]);
+        assert_close(srgb_to_lab([0, 255, 0]), [87.73, -86.18, 83.18]);
+        assert_close(srgb_to_lab([0, 0, 255]), [32.30, 79.19, -107.86]);
+    }
+}
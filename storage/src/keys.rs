@@ -0,0 +1,23 @@
+//! Keys of the stored files. tag_manager writes them and tag_api serves and
+//! deletes them, so both name them through these.
+
+/// The original, always stored as PNG.
+pub fn original_key(id: u32) -> String {
+    format!("{id}.png")
+}
+
+/// A thumbnail rendition. `variant` is `fit` or `square` and `format` the name
+/// recorded in the `thumbnail` table: `jpeg`, `webp` or `avif`.
+pub fn thumbnail_key(id: u32, variant: &str, size: u32, format: &str) -> String {
+    let extension = if format == "jpeg" { "jpg" } else { format };
+    if variant == "square" {
+        format!("{id}_thumbnail_square_{size}.{extension}")
+    } else {
+        format!("{id}_thumbnail_{size}.{extension}")
+    }
+}
+
+/// The single JPEG thumbnail written before renditions existed.
+pub fn legacy_thumbnail_key(id: u32) -> String {
+    format!("{id}_thumbnail.jpg")
+}
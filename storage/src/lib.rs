@@ -0,0 +1,239 @@
+//! Where originals and thumbnails live, shared by tag_manager and tag_api. Objects
+//! are addressed by key (`{id}.png`, `{id}_thumbnail_600.webp`, ...), which is the
+//! file name on local disk and the object key in a bucket. Also home to what else
+//! the two have to agree on, such as how colors are converted for palettes.
+
+pub mod color;
+pub mod keys;
+
+use std::{path::PathBuf, pin::Pin, time::Duration};
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use s3::{Bucket, Region, creds::Credentials, error::S3Error};
+use tokio_util::io::ReaderStream;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+#[derive(Clone, Debug)]
+pub enum StorageConfig {
+    Local(PathBuf),
+    S3(S3Config),
+}
+
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Set for MinIO and other S3-compatible stores, unset for AWS.
+    pub endpoint: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    pub path_style: bool,
+}
+
+impl StorageConfig {
+    /// Reads `STORAGE_BACKEND` (`local` or `s3`) and the settings of that backend.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).map_err(|_| anyhow!("{name} is required"));
+        match std::env::var("STORAGE_BACKEND")
+            .unwrap_or("local".to_string())
+            .as_str()
+        {
+            "local" => Ok(StorageConfig::Local(
+                std::env::var("STORAGE_DIR")
+                    .unwrap_or("/Images/Storage".to_string())
+                    .into(),
+            )),
+            "s3" => {
+                let endpoint = std::env::var("S3_ENDPOINT").ok();
+                Ok(StorageConfig::S3(S3Config {
+                    bucket: var("S3_BUCKET")?,
+                    region: std::env::var("S3_REGION").unwrap_or("us-east-1".to_string()),
+                    access_key: var("S3_ACCESS_KEY")?,
+                    secret_key: var("S3_SECRET_KEY")?,
+                    // MinIO only supports path style addressing out of the box.
+                    path_style: std::env::var("S3_PATH_STYLE")
+                        .map(|x| x.parse().expect("S3_PATH_STYLE not a valid bool"))
+                        .unwrap_or(endpoint.is_some()),
+                    endpoint,
+                }))
+            }
+            other => Err(anyhow!("Unknown STORAGE_BACKEND: {other}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Storage {
+    Local(PathBuf),
+    S3(Box<Bucket>),
+}
+
+impl Storage {
+    pub fn new(config: &StorageConfig) -> Result<Self> {
+        match config {
+            StorageConfig::Local(path) => Ok(Storage::Local(path.clone())),
+            StorageConfig::S3(config) => {
+                let region = match &config.endpoint {
+                    Some(endpoint) => Region::Custom {
+                        region: config.region.clone(),
+                        endpoint: endpoint.clone(),
+                    },
+                    None => config.region.parse()?,
+                };
+                let credentials = Credentials::new(
+                    Some(&config.access_key),
+                    Some(&config.secret_key),
+                    None,
+                    None,
+                    None,
+                )?;
+                let bucket = Bucket::new(&config.bucket, region, credentials)?;
+                Ok(Storage::S3(if config.path_style {
+                    bucket.with_path_style()
+                } else {
+                    bucket
+                }))
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        match self {
+            Storage::Local(path) => tokio::fs::write(path.join(key), bytes).await?,
+            Storage::S3(bucket) => {
+                bucket.put_object(key, bytes).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The whole object, or `None` if it doesn't exist.
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Storage::Local(path) => match tokio::fs::read(path.join(key)).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Storage::S3(bucket) => match bucket.get_object(key).await {
+                Ok(response) => Ok(Some(response.to_vec())),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Like `get`, without holding the whole object in memory.
+    pub async fn stream(&self, key: &str) -> Result<Option<ByteStream>> {
+        match self {
+            Storage::Local(path) => match tokio::fs::File::open(path.join(key)).await {
+                Ok(file) => Ok(Some(ReaderStream::new(file).boxed())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Storage::S3(bucket) => match bucket.get_object_stream(key).await {
+                Ok(response) => Ok(Some(
+                    response
+                        .bytes
+                        .map_err(|e| std::io::Error::other(e.to_string()))
+                        .boxed(),
+                )),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        match self {
+            Storage::Local(path) => Ok(tokio::fs::try_exists(path.join(key)).await?),
+            Storage::S3(bucket) => match bucket.head_object(key).await {
+                Ok(_) => Ok(true),
+                Err(e) if is_not_found(&e) => Ok(false),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Deletes an object. Deleting one that doesn't exist is not an error.
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Storage::Local(path) => match tokio::fs::remove_file(path.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            Storage::S3(bucket) => match bucket.delete_object(key).await {
+                Err(e) if !is_not_found(&e) => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Keys of every stored object.
+    pub async fn list(&self) -> Result<Vec<String>> {
+        match self {
+            Storage::Local(path) => {
+                let mut keys = Vec::new();
+                let mut entries = tokio::fs::read_dir(path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_file()
+                        && let Some(name) = entry.file_name().to_str()
+                    {
+                        keys.push(name.to_string());
+                    }
+                }
+                Ok(keys)
+            }
+            Storage::S3(bucket) => Ok(bucket
+                .list(String::new(), None)
+                .await?
+                .into_iter()
+                .flat_map(|x| x.contents)
+                .map(|x| x.key)
+                .collect()),
+        }
+    }
+
+    /// Total size of every stored object, in bytes.
+    pub async fn usage(&self) -> Result<u64> {
+        match self {
+            Storage::Local(path) => {
+                let mut total = 0;
+                let mut entries = tokio::fs::read_dir(path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let metadata = entry.metadata().await?;
+                    if metadata.is_file() {
+                        total += metadata.len();
+                    }
+                }
+                Ok(total)
+            }
+            Storage::S3(bucket) => Ok(bucket
+                .list(String::new(), None)
+                .await?
+                .into_iter()
+                .flat_map(|x| x.contents)
+                .map(|x| x.size)
+                .sum()),
+        }
+    }
+
+    /// A URL clients can fetch the object from directly, for backends that have one.
+    pub async fn presigned_url(&self, key: &str, expires: Duration) -> Result<Option<String>> {
+        match self {
+            Storage::Local(_) => Ok(None),
+            Storage::S3(bucket) => Ok(Some(
+                bucket
+                    .presign_get(key, expires.as_secs() as u32, None)
+                    .await?,
+            )),
+        }
+    }
+}
+
+fn is_not_found(error: &S3Error) -> bool {
+    matches!(error, S3Error::HttpFailWithBody(404, _))
+}
@@ -0,0 +1,9 @@
+/// Parses `rrggbb`, with or without a leading `#`.
+pub fn parse_hex(value: &str) -> Option<[u8; 3]> {
+    let value = value.trim().trim_start_matches('#');
+    if value.len() != 6 {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(value.get(index..index + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
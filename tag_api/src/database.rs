@@ -1,28 +1,32 @@
-use std::{fmt::Display, path::PathBuf, sync::OnceLock};
+use std::{collections::HashMap, fmt::Display, sync::OnceLock};
 
-use actix_web::web::to;
 use serde::{Deserialize, Serialize};
+use storage::{Storage, keys};
 
-use crate::response::{ImageDbInfo};
+use crate::response::{ImageDbInfo, ServiceStatus, TaskRun};
 
 pub trait Database {
     async fn get_image_location(
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<PathBuf, SqlDatabaseError>;
+    ) -> Result<String, SqlDatabaseError>;
 
-    async fn get_thumbnail_location(
+    async fn get_thumbnail_renditions(
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<PathBuf, SqlDatabaseError>;
+    ) -> Result<Vec<Rendition>, SqlDatabaseError>;
+
+    async fn get_thumbnail_renditions_for(
+        &self,
+        ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<Rendition>>, sqlx::error::Error>;
 
     async fn get_filtered_images_paginated(
         &self,
-        characters: Option<Vec<&str>>,
-        tags: Option<Vec<&str>>,
-        rating: Option<Rating>,
+        filter: &ImageFilter<'_>,
+        auth_level: AuthLevel,
         per_page: u32,
         page: u32,
     ) -> Result<PaginatedResult<Image>, sqlx::error::Error>;
@@ -44,7 +48,36 @@ pub trait Database {
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<ImageDbInfo, sqlx::error::Error>;
+    ) -> Result<ImageDbInfo, SqlDatabaseError>;
+    async fn get_service_status(&self) -> Result<Vec<ServiceStatus>, sqlx::error::Error>;
+    async fn get_task_runs(&self) -> Result<Vec<TaskRun>, sqlx::error::Error>;
+    async fn set_crop(
+        &self,
+        id: u32,
+        x: u32,
+        y: u32,
+        size: Option<u32>,
+    ) -> Result<(), SqlDatabaseError>;
+    async fn delete_image(
+        &self,
+        id: u32,
+        block_reason: Option<&str>,
+    ) -> Result<Vec<String>, SqlDatabaseError>;
+    async fn get_blocked_hashes(
+        &self,
+        per_page: u32,
+        page: u32,
+    ) -> Result<PaginatedResult<BlockedHash>, sqlx::error::Error>;
+    async fn block_hash(&self, hash: &[u8; 8], reason: &str) -> Result<(), sqlx::error::Error>;
+    async fn unblock_hash(&self, hash: &[u8; 8]) -> Result<(), SqlDatabaseError>;
+    async fn can_upload(&self, token: &str) -> Result<bool, sqlx::error::Error>;
+    async fn create_upload_jobs(
+        &self,
+        paths: &[String],
+        defaults: &serde_json::Value,
+        token: &str,
+    ) -> Result<Vec<i32>, sqlx::error::Error>;
+    async fn get_upload_job(&self, id: u32, token: &str) -> Result<UploadJob, SqlDatabaseError>;
 }
 
 #[derive(Debug, Clone, Copy, sqlx::Type, PartialEq, Eq)]
@@ -60,7 +93,6 @@ pub enum AuthLevel {
 pub struct PaginatedResult<T> {
     pub items: Vec<T>,
     pub total_items: u32,
-    pub total_pages: u32,
 }
 
 impl AuthLevel {
@@ -72,6 +104,15 @@ impl AuthLevel {
             AuthLevel::Guest => false,
         }
     }
+
+    /// Images without a rating have not been tagged yet and, like private images,
+    /// are admin-only.
+    fn can_view(&self, rating: Option<Rating>, private: bool) -> bool {
+        match rating {
+            Some(rating) if !private => self.is_allowed(rating),
+            _ => *self == AuthLevel::Admin,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, sqlx::Type, PartialEq, Eq, Deserialize, Serialize)]
@@ -95,7 +136,15 @@ pub struct SqlDatabase {
     pool: sqlx::postgres::PgPool,
 }
 
-pub static IMAGE_PATH: OnceLock<PathBuf> = OnceLock::new();
+pub static STORAGE: OnceLock<Storage> = OnceLock::new();
+
+pub fn storage() -> &'static Storage {
+    STORAGE.get().unwrap()
+}
+
+pub fn thumbnail_key(id: u32, rendition: &Rendition) -> String {
+    keys::thumbnail_key(id, &rendition.variant, rendition.size as u32, &rendition.format)
+}
 
 impl SqlDatabase {
     pub async fn new(connection_string: &str) -> Result<Self, sqlx::error::Error> {
@@ -110,9 +159,9 @@ impl Database for SqlDatabase {
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<PathBuf, SqlDatabaseError> {
+    ) -> Result<String, SqlDatabaseError> {
         let record = sqlx::query!(
-            "SELECT id, rating as \"rating:Rating\" FROM image WHERE id = $1 LIMIT 1",
+            "SELECT id, rating as \"rating:Rating\", private FROM image WHERE id = $1 AND NOT broken LIMIT 1",
             id as i32
         )
         .fetch_optional(&self.pool)
@@ -120,22 +169,20 @@ impl Database for SqlDatabase {
         .map_err(SqlDatabaseError::SqlxError)?
         .ok_or(SqlDatabaseError::NotFound)?;
 
-        if auth_level.is_allowed(record.rating) {
-            let path = IMAGE_PATH.get().unwrap().join(format!("{}.png", record.id));
-
-            Ok(path)
+        if auth_level.can_view(record.rating, record.private) {
+            Ok(keys::original_key(record.id as u32))
         } else {
             Err(SqlDatabaseError::NotAllowed)
         }
     }
 
-    async fn get_thumbnail_location(
+    async fn get_thumbnail_renditions(
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<PathBuf, SqlDatabaseError> {
+    ) -> Result<Vec<Rendition>, SqlDatabaseError> {
         let record = sqlx::query!(
-            "SELECT id, rating as \"rating:Rating\" FROM image WHERE id = $1 AND thumbnail=true LIMIT 1",
+            "SELECT id, rating as \"rating:Rating\", private FROM image WHERE id = $1 LIMIT 1",
             id as i32
         )
         .fetch_optional(&self.pool)
@@ -143,29 +190,65 @@ impl Database for SqlDatabase {
         .map_err(SqlDatabaseError::SqlxError)?
         .ok_or(SqlDatabaseError::NotFound)?;
 
-        if auth_level.is_allowed(record.rating) {
-            let path = IMAGE_PATH
-                .get()
-                .unwrap()
-                .join(format!("{}_thumbnail.jpg", record.id));
+        if !auth_level.can_view(record.rating, record.private) {
+            return Err(SqlDatabaseError::NotAllowed);
+        }
+
+        sqlx::query_as!(
+            Rendition,
+            r#"
+            SELECT variant, size, format, width
+            FROM thumbnail
+            WHERE image_id = $1
+            ORDER BY size
+            "#,
+            record.id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)
+    }
 
-            Ok(path)
-        } else {
-            Err(SqlDatabaseError::NotAllowed)
+    async fn get_thumbnail_renditions_for(
+        &self,
+        ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<Rendition>>, sqlx::error::Error> {
+        let records = sqlx::query!(
+            "SELECT image_id, variant, size, format, width FROM thumbnail WHERE image_id = ANY($1) ORDER BY size",
+            ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut renditions: HashMap<i32, Vec<Rendition>> = HashMap::new();
+        for record in records {
+            renditions.entry(record.image_id).or_default().push(Rendition {
+                variant: record.variant,
+                size: record.size,
+                format: record.format,
+                width: record.width,
+            });
         }
+        Ok(renditions)
     }
 
     async fn get_filtered_images_paginated(
         &self,
-        characters: Option<Vec<&str>>,
-        tags: Option<Vec<&str>>,
-        rating: Option<Rating>,
+        filter: &ImageFilter<'_>,
+        auth_level: AuthLevel,
         per_page: u32,
         page: u32,
     ) -> Result<PaginatedResult<Image>, sqlx::error::Error> {
-        let tag_slice = tags.as_deref();
-        let character_slice = characters.as_deref();
+        let tag_slice = filter.tags.as_deref();
+        let character_slice = filter.characters.as_deref();
+        let (rating, color, monochrome) = (filter.rating, filter.color, filter.monochrome);
+        // Untagged and private images are only listed for admins.
+        let include_hidden = auth_level == AuthLevel::Admin;
 
+        let lab = color.map(|x| x.lab);
+
+        // Distance to the closest palette color that covers a meaningful part of
+        // the image, so a few specks of blue don't make a picture "blue".
         let images = sqlx::query_as(
             r#"
             SELECT i.*
@@ -174,17 +257,31 @@ impl Database for SqlDatabase {
             LEFT JOIN tag t ON t.id = ti.tag_id
             LEFT JOIN character_images ci ON ci.image_id = i.id
             LEFT JOIN character c ON c.id = ci.character_id
+            LEFT JOIN LATERAL (
+                SELECT MIN(sqrt(power(ic.l - $7, 2) + power(ic.a - $8, 2) + power(ic.b - $9, 2))) AS distance
+                FROM image_color ic
+                WHERE ic.image_id = i.id AND ic.weight >= 0.05
+            ) cd ON $7 IS NOT NULL
             WHERE 
                 ($1 IS NULL OR t.tag = ANY($1::text[]))
             AND
                 ($2 IS NULL OR c.character = ANY($2::text[]))
             AND 
                 ($3 IS NULL OR i.rating = $3)
-            GROUP BY i.id
+            AND
+                ((i.tagged AND NOT i.private) OR $6)
+            AND
+                NOT i.broken
+            AND
+                ($7 IS NULL OR cd.distance <= $10)
+            AND
+                ($11 IS NULL OR i.grayscale = $11)
+            GROUP BY i.id, cd.distance
             HAVING
                 ($1 IS NULL OR COUNT(DISTINCT t.tag) = cardinality($1))
             AND
                 ($2 IS NULL OR COUNT(DISTINCT c.character) = cardinality($2))
+            ORDER BY cd.distance NULLS LAST, i.id
             LIMIT $4
             OFFSET $5
             "#,
@@ -194,6 +291,12 @@ impl Database for SqlDatabase {
         .bind(rating)
         .bind(per_page as i32)
         .bind((page * per_page) as i32)
+        .bind(include_hidden)
+        .bind(lab.map(|x| x[0]))
+        .bind(lab.map(|x| x[1]))
+        .bind(lab.map(|x| x[2]))
+        .bind(color.map(|x| x.tolerance))
+        .bind(monochrome)
         .fetch_all(&self.pool)
         .await?;
 
@@ -206,12 +309,25 @@ impl Database for SqlDatabase {
         LEFT JOIN tag t ON t.id = ti.tag_id
         LEFT JOIN character_images ci ON ci.image_id = i.id
         LEFT JOIN character c ON c.id = ci.character_id
+        LEFT JOIN LATERAL (
+            SELECT MIN(sqrt(power(ic.l - $5, 2) + power(ic.a - $6, 2) + power(ic.b - $7, 2))) AS distance
+            FROM image_color ic
+            WHERE ic.image_id = i.id AND ic.weight >= 0.05
+        ) cd ON $5 IS NOT NULL
         WHERE 
             ($1 IS NULL OR t.tag = ANY($1::text[]))
         AND
             ($2 IS NULL OR c.character = ANY($2::text[]))
         AND 
             ($3 IS NULL OR i.rating = $3)
+        AND
+            ((i.tagged AND NOT i.private) OR $4)
+        AND
+            NOT i.broken
+        AND
+            ($5 IS NULL OR cd.distance <= $8)
+        AND
+            ($9 IS NULL OR i.grayscale = $9)
         GROUP BY i.id
         HAVING
             ($1 IS NULL OR COUNT(DISTINCT t.tag) = cardinality($1))
@@ -223,6 +339,12 @@ impl Database for SqlDatabase {
         .bind(tag_slice)
         .bind(character_slice)
         .bind(rating)
+        .bind(include_hidden)
+        .bind(lab.map(|x| x[0]))
+        .bind(lab.map(|x| x[1]))
+        .bind(lab.map(|x| x[2]))
+        .bind(color.map(|x| x.tolerance))
+        .bind(monochrome)
         .fetch_one(&self.pool)
         .await?;
         let total_items: u32 = count as u32;
@@ -230,7 +352,6 @@ impl Database for SqlDatabase {
         Ok(PaginatedResult {
             items: images,
             total_items,
-            total_pages: total_items.div_ceil(per_page),
         })
     }
 
@@ -280,7 +401,6 @@ impl Database for SqlDatabase {
         Ok(PaginatedResult {
             items: tags,
             total_items,
-            total_pages: total_items.div_ceil(per_page),
         })
     }
 
@@ -342,14 +462,28 @@ impl Database for SqlDatabase {
         Ok(PaginatedResult {
             items: tags,
             total_items,
-            total_pages: total_items.div_ceil(per_page),
         })
     }
     async fn get_image_information(
         &self,
         id: u32,
         auth_level: AuthLevel,
-    ) -> Result<ImageDbInfo, sqlx::error::Error> {
+    ) -> Result<ImageDbInfo, SqlDatabaseError> {
+        let record = sqlx::query!(
+            "SELECT rating as \"rating:Rating\", tagged, private, blurhash, grayscale, transparent FROM image WHERE id = $1;",
+            id as i64
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?
+        .ok_or(SqlDatabaseError::NotFound)?;
+
+        // An untagged row has no settled rating yet, so only admins may see it.
+        let rating = record.rating.filter(|_| record.tagged);
+        if !auth_level.can_view(rating, record.private) {
+            return Err(SqlDatabaseError::NotAllowed);
+        }
+
         let tags: Vec<String> = sqlx::query_scalar!(
             r#"
             SELECT tag FROM tag
@@ -359,7 +493,8 @@ impl Database for SqlDatabase {
             id as i64
         )
         .fetch_all(&self.pool)
-        .await?;
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?;
 
         let characters: Vec<String> = sqlx::query_scalar!(
             r#"
@@ -370,34 +505,343 @@ impl Database for SqlDatabase {
             id as i64
         )
         .fetch_all(&self.pool)
-        .await?;
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?;
 
-        let rating = sqlx::query_scalar!(
-            "SELECT rating as \"rating:Rating\" FROM image WHERE id = $1;",
+        let palette: Vec<String> = sqlx::query!(
+            "SELECT red, green, blue FROM image_color WHERE image_id = $1 ORDER BY position;",
             id as i64
         )
-        .fetch_one(&self.pool)
-        .await?;
+        .fetch_all(&self.pool)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?
+        .into_iter()
+        .map(|x| format!("#{:02x}{:02x}{:02x}", x.red, x.green, x.blue))
+        .collect();
 
         let imageinfo = ImageDbInfo{
             id,
             tags,
             characters,
-            rating
+            rating: record.rating,
+            blurhash: record.blurhash,
+            palette,
+            grayscale: record.grayscale,
+            transparent: record.transparent,
         };
 
         Ok(imageinfo)
     }
+
+    async fn set_crop(
+        &self,
+        id: u32,
+        x: u32,
+        y: u32,
+        size: Option<u32>,
+    ) -> Result<(), SqlDatabaseError> {
+        // Clearing the thumbnail flag makes tag_manager re-render with the new crop.
+        let result = sqlx::query!(
+            r#"
+            UPDATE image
+            SET crop_x = $2, crop_y = $3, crop_size = $4, crop_override = true, thumbnail = false
+            WHERE id = $1
+            "#,
+            id as i32,
+            x as i32,
+            y as i32,
+            size.map(|x| x as i32)
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?;
+
+        if result.rows_affected() == 0 {
+            return Err(SqlDatabaseError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Deletes an image and its rows, blocking its hash first when `block_reason`
+    /// is given. Returns the storage keys of its files, which the caller removes.
+    async fn delete_image(
+        &self,
+        id: u32,
+        block_reason: Option<&str>,
+    ) -> Result<Vec<String>, SqlDatabaseError> {
+        let id = id as i32;
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(SqlDatabaseError::SqlxError)?;
+
+        let hash = sqlx::query_scalar!("SELECT hash FROM image WHERE id = $1 FOR UPDATE", id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(SqlDatabaseError::SqlxError)?
+            .ok_or(SqlDatabaseError::NotFound)?;
+        if let Some(reason) = block_reason {
+            sqlx::query!(
+                r#"
+                INSERT INTO blocked_hash (hash, reason, image_id) VALUES ($1, $2, $3)
+                ON CONFLICT (hash) DO UPDATE SET reason = EXCLUDED.reason, image_id = EXCLUDED.image_id
+                "#,
+                hash,
+                reason,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(SqlDatabaseError::SqlxError)?;
+        }
+
+        let renditions = sqlx::query_as!(
+            Rendition,
+            "SELECT variant, size, format, width FROM thumbnail WHERE image_id = $1",
+            id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?;
+
+        for table in ["thumbnail", "image_color", "tag_images", "character_images"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE image_id = $1"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(SqlDatabaseError::SqlxError)?;
+        }
+        sqlx::query!("DELETE FROM image WHERE id = $1", id)
+            .execute(&mut *tx)
+            .await
+            .map_err(SqlDatabaseError::SqlxError)?;
+        tx.commit().await.map_err(SqlDatabaseError::SqlxError)?;
+
+        let id = id as u32;
+        let mut files = vec![keys::original_key(id), keys::legacy_thumbnail_key(id)];
+        files.extend(renditions.iter().map(|x| thumbnail_key(id, x)));
+        Ok(files)
+    }
+
+    async fn get_blocked_hashes(
+        &self,
+        per_page: u32,
+        page: u32,
+    ) -> Result<PaginatedResult<BlockedHash>, sqlx::error::Error> {
+        let items = sqlx::query_as!(
+            BlockedHash,
+            r#"
+            SELECT hash, reason, image_id, extract(epoch from created_at)::bigint as "created_at!"
+            FROM blocked_hash
+            ORDER BY created_at DESC
+            LIMIT $1
+            OFFSET $2
+            "#,
+            per_page as i64,
+            (page * per_page) as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let count = sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM blocked_hash"#)
+            .fetch_one(&self.pool)
+            .await?;
+        let total_items = count as u32;
+
+        Ok(PaginatedResult {
+            items,
+            total_items,
+        })
+    }
+
+    async fn block_hash(&self, hash: &[u8; 8], reason: &str) -> Result<(), sqlx::error::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO blocked_hash (hash, reason) VALUES ($1, $2)
+            ON CONFLICT (hash) DO UPDATE SET reason = EXCLUDED.reason
+            "#,
+            hash.as_slice(),
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unblock_hash(&self, hash: &[u8; 8]) -> Result<(), SqlDatabaseError> {
+        let result = sqlx::query!("DELETE FROM blocked_hash WHERE hash = $1", hash.as_slice())
+            .execute(&self.pool)
+            .await
+            .map_err(SqlDatabaseError::SqlxError)?;
+
+        if result.rows_affected() == 0 {
+            return Err(SqlDatabaseError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Admin tokens may always upload, others only with `can_upload` set.
+    async fn can_upload(&self, token: &str) -> Result<bool, sqlx::error::Error> {
+        let allowed = sqlx::query_scalar!(
+            r#"
+            SELECT (can_upload OR level = 'Admin') as "allowed!"
+            FROM auth WHERE token = digest($1, 'sha256')
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(allowed.unwrap_or(false))
+    }
+
+    /// Queues uploaded files for tag_manager, all or none. `defaults` replaces the
+    /// import root defaults of every file.
+    async fn create_upload_jobs(
+        &self,
+        paths: &[String],
+        defaults: &serde_json::Value,
+        token: &str,
+    ) -> Result<Vec<i32>, sqlx::error::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(paths.len());
+        for path in paths {
+            let id = sqlx::query_scalar!(
+                r#"
+                INSERT INTO ingest_job (path, defaults, uploaded_by)
+                VALUES ($1, $2, digest($3, 'sha256'))
+                RETURNING id
+                "#,
+                path,
+                defaults,
+                token
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            ids.push(id);
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    /// An upload's job, if it was uploaded with `token`.
+    async fn get_upload_job(&self, id: u32, token: &str) -> Result<UploadJob, SqlDatabaseError> {
+        sqlx::query_as!(
+            UploadJob,
+            r#"
+            SELECT id, stage, image_id, error FROM ingest_job
+            WHERE id = $1 AND uploaded_by = digest($2, 'sha256')
+            "#,
+            id as i32,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(SqlDatabaseError::SqlxError)?
+        .ok_or(SqlDatabaseError::NotFound)
+    }
+
+    async fn get_service_status(&self) -> Result<Vec<ServiceStatus>, sqlx::error::Error> {
+        sqlx::query_as!(
+            ServiceStatus,
+            r#"
+            SELECT component, state, detail, extract(epoch from updated_at)::bigint as "updated_at!"
+            FROM service_status
+            ORDER BY component
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// The latest run of every scheduled task.
+    async fn get_task_runs(&self) -> Result<Vec<TaskRun>, sqlx::error::Error> {
+        sqlx::query_as!(
+            TaskRun,
+            r#"
+            SELECT DISTINCT ON (task)
+                task, worker_id, outcome, detail,
+                extract(epoch from started_at)::bigint as "started_at!",
+                extract(epoch from finished_at)::bigint as finished_at,
+                (extract(epoch from finished_at - started_at) * 1000)::bigint as duration_ms
+            FROM scheduled_task_run
+            ORDER BY task, started_at DESC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+/// What a search is narrowed down to, each filter applying only when set.
+#[derive(Debug, Default)]
+pub struct ImageFilter<'a> {
+    pub characters: Option<Vec<&'a str>>,
+    pub tags: Option<Vec<&'a str>>,
+    pub rating: Option<Rating>,
+    pub color: Option<ColorFilter>,
+    pub monochrome: Option<bool>,
+}
+
+/// Limits a search to images with a palette color within `tolerance` (CIE76 ΔE)
+/// of `lab`, closest first.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorFilter {
+    pub lab: [f32; 3],
+    pub tolerance: f32,
+}
+
+/// An entry of the import blocklist. `image_id` is the deleted image the hash was
+/// taken from, if any.
+#[derive(Debug)]
+pub struct BlockedHash {
+    pub hash: Vec<u8>,
+    pub reason: String,
+    pub image_id: Option<i32>,
+    pub created_at: i64,
+}
+
+/// The `ingest_job` row of an uploaded file. `image_id` is unset until it is
+/// stored, and again if the image is deleted later.
+#[derive(Debug)]
+pub struct UploadJob {
+    pub id: i32,
+    pub stage: String,
+    pub image_id: Option<i32>,
+    pub error: Option<String>,
 }
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct Image {
     pub id: i32,
+    pub blurhash: Option<String>,
+}
+
+/// One stored thumbnail of an image. `size` is the bounding box it was rendered
+/// for, `width` its actual width.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub variant: String,
+    pub size: i32,
+    pub format: String,
+    pub width: i32,
+}
+
+impl Rendition {
+    pub fn content_type(&self) -> &'static str {
+        match self.format.as_str() {
+            "webp" => "image/webp",
+            "avif" => "image/avif",
+            _ => "image/jpeg",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SqlDatabaseError {
     NotFound,
-    SqlxError(sqlx::error::Error),
+    // Only read through `Debug` when the error is logged.
+    SqlxError(#[allow(dead_code)] sqlx::error::Error),
     NotAllowed,
 }
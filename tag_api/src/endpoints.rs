@@ -1,27 +1,103 @@
-use std::{fmt::format, sync::OnceLock};
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
 
+use actix_multipart::Multipart;
 use actix_web::{
-    get,
-    http::StatusCode,
+    HttpRequest, delete, get, post,
+    http::{StatusCode, header},
     web::{self},
 };
+use futures_util::StreamExt;
 use log::error;
-use tokio::io::AsyncReadExt;
+use serde_json::json;
+use storage::keys;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 
 use crate::{
-    database::{Database, SqlDatabase, SqlDatabaseError},
-    requests::{FindCharacterQuery, FindImageRequest, FindTagQuery, ImageRequest},
-    response::{ApiResponse, CharacterData, ImageInfo, Imagedata, PaginatedResponse, TagData},
+    color,
+    database::{
+        AuthLevel, ColorFilter, Database, ImageFilter, Rendition, SqlDatabase, SqlDatabaseError,
+        storage, thumbnail_key,
+    },
+    requests::{
+        BlockHashRequest, BlocklistQuery, CropRequest, DeleteImageRequest, FindCharacterQuery,
+        FindImageRequest, FindTagQuery, ImageRequest, ThumbnailRequest, UploadSidecar,
+    },
+    response::{
+        ApiResponse, BlockedHashData, CharacterData, ImageInfo, Imagedata, PaginatedResponse,
+        ServiceStatus, TagData, TaskRun, ThumbnailSource, UploadStatus, UploadedFile,
+    },
 };
 
 pub static IMAGE_PREFIX: OnceLock<String> = OnceLock::new();
+pub static DEFAULT_THUMBNAIL_SIZE: OnceLock<u32> = OnceLock::new();
+/// Lifetime of presigned URLs when files are served by redirecting to the object
+/// store, `None` to stream them through the API instead.
+pub static REDIRECT_EXPIRY: OnceLock<Option<Duration>> = OnceLock::new();
 pub static MAX_PER_PAGE: u32 = 400;
+/// CIELAB distance within which colors are considered a match by default.
+pub static DEFAULT_COLOR_TOLERANCE: f32 = 20.0;
+/// Where uploaded files wait for tag_manager, which has to see it at the same path.
+pub static UPLOAD_DIR: OnceLock<PathBuf> = OnceLock::new();
+pub static UPLOAD_MAX_BYTES: OnceLock<u64> = OnceLock::new();
+pub static MAX_SIDECAR_SIZE: usize = 64 * 1024;
+
+/// Resolves a request token to its auth level, treating missing or unknown tokens as guests.
+async fn auth_level(data: &SqlDatabase, token: Option<&str>) -> AuthLevel {
+    let Some(token) = token else {
+        return AuthLevel::Guest;
+    };
+    match data.get_auth_level(token).await {
+        Ok(level) => level,
+        Err(SqlDatabaseError::NotFound) => AuthLevel::Guest,
+        Err(SqlDatabaseError::NotAllowed) => unreachable!(),
+        Err(e) => {
+            error!("Unable to get level, falling back to guest: {e:?}");
+            AuthLevel::Guest
+        }
+    }
+}
 
 #[get("/")]
 async fn root(_: web::Data<SqlDatabase>) -> ApiResponse<&'static str, ()> {
     ApiResponse::new_success("Site up and working")
 }
 
+#[get("/status")]
+async fn status(data: web::Data<SqlDatabase>) -> ApiResponse<Vec<ServiceStatus>, &'static str> {
+    match data.get_service_status().await {
+        Ok(status) => ApiResponse::new_success(status),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
+
+/// The latest run of each tag_manager maintenance task.
+#[get("/admin/tasks")]
+async fn tasks(
+    data: web::Data<SqlDatabase>,
+    query: web::Query<ImageRequest>,
+) -> ApiResponse<Vec<TaskRun>, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+
+    match data.get_task_runs().await {
+        Ok(runs) => ApiResponse::new_success(runs),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
+
 #[get("/image/{id}")]
 async fn image(
     data: web::Data<SqlDatabase>,
@@ -29,22 +105,10 @@ async fn image(
     query: web::Query<ImageRequest>,
 ) -> ApiResponse<(), &'static str> {
     let id = id.into_inner();
-    let level = if let Some(token) = &query.token {
-        match data.get_auth_level(token).await {
-            Ok(level) => level,
-            Err(SqlDatabaseError::NotFound) => crate::database::AuthLevel::Guest,
-            Err(SqlDatabaseError::NotAllowed) => unreachable!(),
-            Err(e) => {
-                error!("Unable to get level, falling back to guest: {:?}", e);
-                crate::database::AuthLevel::Guest
-            }
-        }
-    } else {
-        crate::database::AuthLevel::Guest
-    };
+    let level = auth_level(&data, query.token.as_deref()).await;
 
-    let path = match data.get_image_location(id, level).await {
-        Ok(path) => path,
+    let key = match data.get_image_location(id, level).await {
+        Ok(key) => key,
         Err(SqlDatabaseError::NotFound) => {
             return ApiResponse::new_bad_request("Incorrect image id");
         }
@@ -57,77 +121,478 @@ async fn image(
         }
     };
 
-    let mut file = match tokio::fs::File::open(path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Error opening file: {:?}", e);
-            return ApiResponse::new_internal_server_error("Internal server error");
+    match serve_stored(&key, "image/png").await {
+        Some(response) => response,
+        None => {
+            error!("Image {key} missing from storage");
+            ApiResponse::new_internal_server_error("Internal server error")
         }
-    };
-
-    let mut buffer = Vec::new();
-
-    if let Err(e) = file.read_to_end(&mut buffer).await {
-        error!("Error reading file: {:?}", e);
-        return ApiResponse::new_internal_server_error("Internal server error");
     }
-
-    ApiResponse::new_binary(StatusCode::OK, buffer, "image/png")
 }
 
 #[get("/thumbnail/{id}")]
 async fn thumbnail(
+    req: HttpRequest,
     data: web::Data<SqlDatabase>,
     id: web::Path<u32>,
-    query: web::Query<ImageRequest>,
+    query: web::Query<ThumbnailRequest>,
 ) -> ApiResponse<(), &'static str> {
     let id = id.into_inner();
-    let level = if let Some(token) = &query.token {
-        match data.get_auth_level(token).await {
-            Ok(level) => level,
-            Err(SqlDatabaseError::NotFound) => crate::database::AuthLevel::Guest,
-            Err(SqlDatabaseError::NotAllowed) => unreachable!(),
+    let level = auth_level(&data, query.token.as_deref()).await;
+
+    let renditions = match data.get_thumbnail_renditions(id, level).await {
+        Ok(renditions) => renditions,
+        Err(SqlDatabaseError::NotFound) => {
+            return ApiResponse::new_bad_request("Incorrect image id");
+        }
+        Err(SqlDatabaseError::NotAllowed) => {
+            return ApiResponse::new_not_allowed("Not correct permissions for this image");
+        }
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            return ApiResponse::new_internal_server_error("Internal server error");
+        }
+    };
+
+    let (key, content_type) = if renditions.is_empty() {
+        (keys::legacy_thumbnail_key(id), "image/jpeg")
+    } else {
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("");
+        let variant = query.variant.as_deref().unwrap_or("fit");
+        match choose_rendition(&renditions, variant, query.size, query.format.as_deref(), accept) {
+            Some(rendition) => (thumbnail_key(id, rendition), rendition.content_type()),
+            None => {
+                return ApiResponse::new_bad_request("No thumbnail in the requested format");
+            }
+        }
+    };
+
+    match serve_stored(&key, content_type).await {
+        // The format depends on Accept, so caches must not hand an AVIF to a
+        // client that didn't ask for one.
+        Some(response) => response.with_header(header::VARY, "Accept"),
+        None if renditions.is_empty() => {
+            ApiResponse::new_bad_request("Incorrect image id or no thumbnail yet processed")
+        }
+        None => {
+            error!("Thumbnail {key} missing from storage");
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
+
+/// Redirects to a presigned URL when configured and the storage supports it,
+/// otherwise streams the file. `None` if streaming finds no such file; redirects
+/// don't check, the object store answers those with its own 404.
+async fn serve_stored(key: &str, content_type: &str) -> Option<ApiResponse<(), &'static str>> {
+    if let Some(expiry) = *REDIRECT_EXPIRY.get().unwrap() {
+        match storage().presigned_url(key, expiry).await {
+            Ok(Some(url)) => return Some(ApiResponse::new_redirect(url)),
+            Ok(None) => {}
             Err(e) => {
-                error!("Unable to get level, falling back to guest: {:?}", e);
-                crate::database::AuthLevel::Guest
+                error!("Error presigning {key}: {:?}", e);
+                return Some(ApiResponse::new_internal_server_error("Internal server error"));
             }
         }
-    } else {
-        crate::database::AuthLevel::Guest
+    }
+
+    match storage().stream(key).await {
+        Ok(Some(stream)) => Some(ApiResponse::new_stream(StatusCode::OK, stream, content_type)),
+        Ok(None) => None,
+        Err(e) => {
+            error!("Error reading {key}: {:?}", e);
+            Some(ApiResponse::new_internal_server_error("Internal server error"))
+        }
+    }
+}
+
+/// Picks the rendition to serve. An explicit `format` wins, otherwise the best
+/// format the client lists in `Accept`, with JPEG as the fallback everyone takes.
+/// Within a format this is the smallest rendition at least `size` wide, or the
+/// largest one if none is big enough.
+fn choose_rendition<'a>(
+    renditions: &'a [Rendition],
+    variant: &str,
+    size: Option<u32>,
+    format: Option<&str>,
+    accept: &str,
+) -> Option<&'a Rendition> {
+    let formats: Vec<&str> = match format {
+        Some(format) => vec![if format == "jpg" { "jpeg" } else { format }],
+        None => ["avif", "webp"]
+            .into_iter()
+            .filter(|x| accept.contains(&format!("image/{x}")))
+            .chain(["jpeg"])
+            .collect(),
     };
+    let size = size.unwrap_or(*DEFAULT_THUMBNAIL_SIZE.get().unwrap()) as i32;
+
+    formats.into_iter().find_map(|format| {
+        let candidates: Vec<_> = renditions
+            .iter()
+            .filter(|x| x.variant == variant && x.format == format)
+            .collect();
+        candidates
+            .iter()
+            .filter(|x| x.size >= size)
+            .min_by_key(|x| x.size)
+            .or_else(|| candidates.iter().max_by_key(|x| x.size))
+            .copied()
+    })
+}
 
-    let path = match data.get_thumbnail_location(id, level).await {
-        Ok(path) => path,
-        Err(SqlDatabaseError::NotFound) => {
-            return ApiResponse::new_bad_request(
-                "Incorrect image id or no thumbnail yet processed",
-            );
+/// Overrides the square thumbnail crop of an image. Coordinates are in pixels of
+/// the stored image; tag_manager clamps them and re-renders the thumbnails.
+#[post("/admin/image/{id}/crop")]
+async fn set_crop(
+    data: web::Data<SqlDatabase>,
+    id: web::Path<u32>,
+    query: web::Query<ImageRequest>,
+    crop: web::Json<CropRequest>,
+) -> ApiResponse<&'static str, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+
+    match data.set_crop(id.into_inner(), crop.x, crop.y, crop.size).await {
+        Ok(()) => ApiResponse::new_success("Crop updated, thumbnails will be regenerated"),
+        Err(SqlDatabaseError::NotFound) => ApiResponse::new_bad_request("Incorrect image id"),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            ApiResponse::new_internal_server_error("Internal server error")
         }
-        Err(SqlDatabaseError::NotAllowed) => {
-            return ApiResponse::new_not_allowed("Not correct permissions for this image");
+    }
+}
+
+/// Deletes an image and its files. Unless `block=false`, its hash is added to the
+/// blocklist so tag_manager discards it when it is imported again.
+#[delete("/admin/image/{id}")]
+async fn delete_image(
+    data: web::Data<SqlDatabase>,
+    id: web::Path<u32>,
+    query: web::Query<DeleteImageRequest>,
+) -> ApiResponse<&'static str, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+
+    let reason = query.reason.as_deref().unwrap_or("Deleted by an admin");
+    let block_reason = query.block.unwrap_or(true).then_some(reason);
+    let keys = match data.delete_image(id.into_inner(), block_reason).await {
+        Ok(keys) => keys,
+        Err(SqlDatabaseError::NotFound) => return ApiResponse::new_bad_request("Incorrect image id"),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            return ApiResponse::new_internal_server_error("Internal server error");
         }
+    };
+
+    // The rows are gone, so a file that fails to delete here is only an orphan
+    // for the storage audit to report.
+    for key in keys {
+        if let Err(e) = storage().delete(&key).await {
+            error!("Could not delete {key}: {e:?}");
+        }
+    }
+    ApiResponse::new_success("Image deleted")
+}
+
+#[get("/admin/blocklist")]
+async fn blocklist(
+    data: web::Data<SqlDatabase>,
+    query: web::Query<BlocklistQuery>,
+) -> ApiResponse<PaginatedResponse<BlockedHashData>, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+
+    let page = query.pages.page.unwrap_or(0);
+    let per_page = query
+        .pages
+        .per_page
+        .map(|x| if x <= MAX_PER_PAGE { x } else { MAX_PER_PAGE })
+        .unwrap_or(MAX_PER_PAGE);
+    let blocked = match data.get_blocked_hashes(per_page, page).await {
+        Ok(blocked) => blocked,
         Err(e) => {
             error!("sqlx error: {:?}", e);
             return ApiResponse::new_internal_server_error("Internal server error");
         }
     };
 
-    let mut file = match tokio::fs::File::open(path).await {
-        Ok(file) => file,
+    let items = blocked
+        .items
+        .into_iter()
+        .map(|x| BlockedHashData {
+            hash: x.hash.iter().map(|x| format!("{x:02x}")).collect(),
+            reason: x.reason,
+            image_id: x.image_id,
+            created_at: x.created_at,
+        })
+        .collect();
+    ApiResponse::new_success(PaginatedResponse::new(
+        items,
+        "/admin/blocklist?",
+        page,
+        per_page,
+        blocked.total_items,
+    ))
+}
+
+#[post("/admin/blocklist")]
+async fn block_hash(
+    data: web::Data<SqlDatabase>,
+    query: web::Query<ImageRequest>,
+    request: web::Json<BlockHashRequest>,
+) -> ApiResponse<&'static str, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+    let Some(hash) = parse_hash(&request.hash) else {
+        return ApiResponse::new_bad_request("Hash must be 16 hex digits");
+    };
+
+    match data.block_hash(&hash, &request.reason).await {
+        Ok(()) => ApiResponse::new_success("Hash blocked"),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
+
+#[delete("/admin/blocklist/{hash}")]
+async fn unblock_hash(
+    data: web::Data<SqlDatabase>,
+    hash: web::Path<String>,
+    query: web::Query<ImageRequest>,
+) -> ApiResponse<&'static str, &'static str> {
+    let level = auth_level(&data, query.token.as_deref()).await;
+    if level != AuthLevel::Admin {
+        return ApiResponse::new_not_allowed("Admin token required");
+    }
+    let Some(hash) = parse_hash(&hash) else {
+        return ApiResponse::new_bad_request("Hash must be 16 hex digits");
+    };
+
+    match data.unblock_hash(&hash).await {
+        Ok(()) => ApiResponse::new_success("Hash unblocked"),
+        Err(SqlDatabaseError::NotFound) => ApiResponse::new_bad_request("Hash is not blocked"),
         Err(e) => {
-            error!("Error opening file: {:?}", e);
+            error!("sqlx error: {:?}", e);
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
+
+/// Parses an average hash written as 16 hex digits.
+fn parse_hash(value: &str) -> Option<[u8; 8]> {
+    let value = value.trim();
+    if value.len() != 16 {
+        return None;
+    }
+    let mut hash = [0; 8];
+    for (index, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+type UploadResponse = ApiResponse<Vec<UploadedFile>, &'static str>;
+
+/// Saves the files of a multipart upload to `UPLOAD_DIR` and queues them for
+/// tag_manager. Every field with a file name is an image, except `sidecar`, JSON
+/// applied to all of them. Nothing is queued unless every file was saved.
+#[post("/upload")]
+async fn upload(
+    data: web::Data<SqlDatabase>,
+    query: web::Query<ImageRequest>,
+    mut payload: Multipart,
+) -> UploadResponse {
+    let Some(token) = query.token.as_deref() else {
+        return ApiResponse::new_not_allowed("Upload token required");
+    };
+    match data.can_upload(token).await {
+        Ok(true) => {}
+        Ok(false) => return ApiResponse::new_not_allowed("Upload token required"),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
             return ApiResponse::new_internal_server_error("Internal server error");
         }
+    }
+
+    let mut files = Vec::new();
+    let sidecar = match save_upload(&mut payload, &mut files).await {
+        Ok(sidecar) => sidecar,
+        Err(response) => {
+            remove_uploaded(&files).await;
+            return response;
+        }
     };
+    if files.is_empty() {
+        return ApiResponse::new_bad_request("No files in upload");
+    }
 
-    let mut buffer = Vec::new();
+    // In the shape of tag_manager's import root defaults.
+    let defaults = json!({
+        "source": sidecar.source,
+        "tags": sidecar
+            .tags
+            .iter()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>(),
+        "rating": sidecar.rating.map(|x| x.to_string().to_lowercase()),
+    });
+    let paths: Vec<String> = files
+        .iter()
+        .map(|(_, path)| path.to_string_lossy().into_owned())
+        .collect();
+    match data.create_upload_jobs(&paths, &defaults, token).await {
+        Ok(ids) => ApiResponse::new_success(
+            files
+                .into_iter()
+                .zip(ids)
+                .map(|((file, _), job_id)| UploadedFile { file, job_id })
+                .collect(),
+        ),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            remove_uploaded(&files).await;
+            ApiResponse::new_internal_server_error("Internal server error")
+        }
+    }
+}
 
-    if let Err(e) = file.read_to_end(&mut buffer).await {
-        error!("Error reading file: {:?}", e);
-        return ApiResponse::new_internal_server_error("Internal server error");
+/// Writes the files of an upload to `UPLOAD_DIR` under new names, adding each to
+/// `files` with the name it was sent with before it is written, so the caller can
+/// remove partial ones. Returns the sidecar, empty if there was none.
+async fn save_upload(
+    payload: &mut Multipart,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<UploadSidecar, UploadResponse> {
+    let directory = UPLOAD_DIR.get().unwrap();
+    let max_size = *UPLOAD_MAX_BYTES.get().unwrap();
+    let internal_error = |e: std::io::Error| {
+        error!("Could not save upload: {e:?}");
+        UploadResponse::new_internal_server_error("Internal server error")
+    };
+    tokio::fs::create_dir_all(directory)
+        .await
+        .map_err(internal_error)?;
+
+    let mut sidecar = UploadSidecar::default();
+    while let Some(field) = payload.next().await {
+        let mut field =
+            field.map_err(|_| UploadResponse::new_bad_request("Malformed multipart body"))?;
+        if field.name() == Some("sidecar") {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk =
+                    chunk.map_err(|_| UploadResponse::new_bad_request("Upload interrupted"))?;
+                if bytes.len() + chunk.len() > MAX_SIDECAR_SIZE {
+                    return Err(UploadResponse::new_bad_request("Sidecar too large"));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            sidecar = serde_json::from_slice(&bytes)
+                .map_err(|_| UploadResponse::new_bad_request("Invalid sidecar"))?;
+            continue;
+        }
+        let Some(file_name) = field
+            .content_disposition()
+            .and_then(|x| x.get_filename())
+            .map(str::to_string)
+        else {
+            continue;
+        };
+
+        let path = directory.join(format!(
+            "{}.{}",
+            Uuid::new_v4(),
+            upload_extension(&file_name)
+        ));
+        files.push((file_name, path.clone()));
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(internal_error)?;
+        let mut size = 0;
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|_| UploadResponse::new_bad_request("Upload interrupted"))?;
+            size += chunk.len() as u64;
+            if size > max_size {
+                return Err(UploadResponse::new_bad_request("File larger than UPLOAD_MAX_MB"));
+            }
+            file.write_all(&chunk).await.map_err(internal_error)?;
+        }
+        file.flush().await.map_err(internal_error)?;
     }
+    Ok(sidecar)
+}
 
-    ApiResponse::new_binary(StatusCode::OK, buffer, "image/jpg")
+/// The extension of an uploaded file's name if it is a plain one. tag_manager
+/// goes by the contents, so it only helps whoever looks in the directory.
+fn upload_extension(file_name: &str) -> String {
+    Path::new(file_name)
+        .extension()
+        .and_then(|x| x.to_str())
+        .filter(|x| x.len() <= 5 && x.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(|x| x.to_ascii_lowercase())
+        .unwrap_or_else(|| "bin".to_string())
+}
+
+async fn remove_uploaded(files: &[(String, PathBuf)]) {
+    for (_, path) in files {
+        if let Err(e) = tokio::fs::remove_file(path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            error!("Could not remove {}: {e:?}", path.display());
+        }
+    }
+}
+
+/// Where an uploaded file is in the import. Only the token it was uploaded with
+/// can see it.
+#[get("/upload/{job_id}")]
+async fn upload_status(
+    data: web::Data<SqlDatabase>,
+    job_id: web::Path<u32>,
+    query: web::Query<ImageRequest>,
+) -> ApiResponse<UploadStatus, &'static str> {
+    let Some(token) = query.token.as_deref() else {
+        return ApiResponse::new_not_allowed("Upload token required");
+    };
+    let job = match data.get_upload_job(job_id.into_inner(), token).await {
+        Ok(job) => job,
+        Err(SqlDatabaseError::NotFound) => return ApiResponse::new_bad_request("Incorrect job id"),
+        Err(e) => {
+            error!("sqlx error: {:?}", e);
+            return ApiResponse::new_internal_server_error("Internal server error");
+        }
+    };
+
+    let (state, reason) = match job.stage.as_str() {
+        "done" if job.image_id.is_some() => ("done", None),
+        // Imported, then deleted by an admin or a rule.
+        "done" => ("rejected", Some("Removed after import".to_string())),
+        "failed" => ("rejected", job.error),
+        _ => ("pending", None),
+    };
+    ApiResponse::new_success(UploadStatus {
+        job_id: job.id,
+        state,
+        stage: job.stage,
+        image_id: job.image_id,
+        reason,
+    })
 }
 
 #[get("/search")]
@@ -152,8 +617,30 @@ async fn find_images(
         .map(|x| if x <= MAX_PER_PAGE { x } else { MAX_PER_PAGE })
         .unwrap_or(MAX_PER_PAGE);
 
+    let color = match query.color.as_deref().filter(|x| !x.is_empty()) {
+        Some(color) => match color::parse_hex(color) {
+            Some(rgb) => Some(ColorFilter {
+                lab: storage::color::srgb_to_lab(rgb),
+                tolerance: query.tolerance.unwrap_or(DEFAULT_COLOR_TOLERANCE),
+            }),
+            None => {
+                return ApiResponse::new_bad_request("Color must be a hex value like 3366ff");
+            }
+        },
+        None => None,
+    };
+
+    let level = auth_level(&data, query.token.as_deref()).await;
+
+    let filter = ImageFilter {
+        characters,
+        tags,
+        rating: query.rating,
+        color,
+        monochrome: query.monochrome,
+    };
     let paged_result = match data
-        .get_filtered_images_paginated(characters, tags, query.rating, per_page, page)
+        .get_filtered_images_paginated(&filter, level, per_page, page)
         .await
     {
         Ok(ids) => ids,
@@ -163,10 +650,43 @@ async fn find_images(
         }
     };
 
+    let image_ids: Vec<i32> = paged_result.items.iter().map(|x| x.id).collect();
+    let mut renditions = match data.get_thumbnail_renditions_for(&image_ids).await {
+        Ok(renditions) => renditions,
+        Err(e) => {
+            error!("Error: {:?}", e);
+            return ApiResponse::new_internal_server_error("Internal server error");
+        }
+    };
+
     let ids: Vec<_> = paged_result
         .items
         .iter()
         .map(|x| {
+            let thumbnails = renditions
+                .remove(&x.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|rendition| ThumbnailSource {
+                    url: format!(
+                        "{}/thumbnail/{}?variant={}&size={}&format={}{}",
+                        IMAGE_PREFIX.get().unwrap(),
+                        x.id,
+                        rendition.variant,
+                        rendition.size,
+                        rendition.format,
+                        query
+                            .token
+                            .as_ref()
+                            .map(|x| format!("&token={}", x))
+                            .as_ref()
+                            .map_or("", |v| v)
+                    ),
+                    width: rendition.width,
+                    format: rendition.format,
+                    variant: rendition.variant,
+                })
+                .collect();
             Imagedata::new(
                 x.id,
                 format!(
@@ -191,6 +711,8 @@ async fn find_images(
                         .as_ref()
                         .map_or("", |v| v)
                 ),
+                thumbnails,
+                x.blurhash.clone(),
             )
         })
         .collect();
@@ -198,7 +720,7 @@ async fn find_images(
     ApiResponse::new_success(PaginatedResponse::new(
         ids,
         &format!(
-            "/search?{}{}{}{}",
+            "/search?{}{}{}{}{}{}{}",
             query
                 .characters
                 .as_ref()
@@ -216,6 +738,22 @@ async fn find_images(
                 .map(|x| format!("&rating={}", x))
                 .as_ref()
                 .map_or("", |v| v),
+            query
+                .color
+                .as_ref()
+                .map(|x| format!("&color={}", x.trim_start_matches('#')))
+                .as_ref()
+                .map_or("", |v| v),
+            query
+                .tolerance
+                .map(|x| format!("&tolerance={}", x))
+                .as_ref()
+                .map_or("", |v| v),
+            query
+                .monochrome
+                .map(|x| format!("&monochrome={}", x))
+                .as_ref()
+                .map_or("", |v| v),
             query
                 .token
                 .as_ref()
@@ -334,22 +872,16 @@ async fn imageinfo(
     query: web::Query<ImageRequest>,
 ) -> ApiResponse<ImageInfo, &'static str> {
     let id = id.into_inner();
-    let level = if let Some(token) = &query.token {
-        match data.get_auth_level(token).await {
-            Ok(level) => level,
-            Err(SqlDatabaseError::NotFound) => crate::database::AuthLevel::Guest,
-            Err(SqlDatabaseError::NotAllowed) => unreachable!(),
-            Err(e) => {
-                error!("Unable to get level, falling back to guest: {e:?}");
-                crate::database::AuthLevel::Guest
-            }
-        }
-    } else {
-        crate::database::AuthLevel::Guest
-    };
+    let level = auth_level(&data, query.token.as_deref()).await;
 
     let info = match data.get_image_information(id, level).await {
         Ok(info) => info,
+        Err(SqlDatabaseError::NotFound) => {
+            return ApiResponse::new_bad_request("Incorrect image id");
+        }
+        Err(SqlDatabaseError::NotAllowed) => {
+            return ApiResponse::new_not_allowed("Not correct permissions for this image");
+        }
         Err(e) => {
             error!("Unable to get db: {e:?}");
             return ApiResponse::new_internal_server_error("pain");
@@ -360,6 +892,10 @@ async fn imageinfo(
         tags: info.tags,
         characters: info.characters,
         rating: info.rating,
+        blurhash: info.blurhash,
+        palette: info.palette,
+        grayscale: info.grayscale,
+        transparent: info.transparent,
         image_url: format!(
             "{}/image/{}{}",
             IMAGE_PREFIX.get().unwrap(),
@@ -386,3 +922,95 @@ async fn imageinfo(
 
     ApiResponse::new_success(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendition(variant: &str, size: i32, format: &str) -> Rendition {
+        Rendition {
+            variant: variant.to_string(),
+            size,
+            format: format.to_string(),
+            width: size,
+        }
+    }
+
+    fn renditions() -> Vec<Rendition> {
+        vec![
+            rendition("fit", 256, "jpeg"),
+            rendition("fit", 512, "jpeg"),
+            rendition("fit", 256, "webp"),
+            rendition("fit", 512, "webp"),
+            rendition("fit", 256, "avif"),
+            rendition("square", 256, "jpeg"),
+        ]
+    }
+
+    fn choose(
+        variant: &str,
+        size: Option<u32>,
+        format: Option<&str>,
+        accept: &str,
+    ) -> Option<(String, String, i32)> {
+        DEFAULT_THUMBNAIL_SIZE.get_or_init(|| 256);
+        let renditions = renditions();
+        choose_rendition(&renditions, variant, size, format, accept)
+            .map(|x| (x.variant.clone(), x.format.clone(), x.size))
+    }
+
+    fn chosen(variant: &str, format: &str, size: i32) -> Option<(String, String, i32)> {
+        Some((variant.to_string(), format.to_string(), size))
+    }
+
+    #[test]
+    fn best_accepted_format_wins() {
+        assert_eq!(
+            choose("fit", None, None, "image/avif,image/webp,*/*"),
+            chosen("fit", "avif", 256)
+        );
+        assert_eq!(
+            choose("fit", None, None, "image/webp,*/*"),
+            chosen("fit", "webp", 256)
+        );
+        assert_eq!(choose("fit", None, None, "*/*"), chosen("fit", "jpeg", 256));
+        assert_eq!(choose("fit", None, None, ""), chosen("fit", "jpeg", 256));
+    }
+
+    #[test]
+    fn accepted_format_without_the_size_falls_back_to_the_largest() {
+        assert_eq!(
+            choose("fit", Some(512), None, "image/avif"),
+            chosen("fit", "avif", 256)
+        );
+    }
+
+    #[test]
+    fn smallest_rendition_at_least_the_size() {
+        assert_eq!(choose("fit", Some(300), None, ""), chosen("fit", "jpeg", 512));
+        assert_eq!(choose("fit", Some(100), None, ""), chosen("fit", "jpeg", 256));
+        assert_eq!(choose("fit", Some(2000), None, ""), chosen("fit", "jpeg", 512));
+    }
+
+    #[test]
+    fn explicit_format_overrides_accept() {
+        assert_eq!(
+            choose("fit", None, Some("webp"), "image/avif"),
+            chosen("fit", "webp", 256)
+        );
+        assert_eq!(
+            choose("fit", Some(512), Some("jpg"), "image/avif"),
+            chosen("fit", "jpeg", 512)
+        );
+    }
+
+    #[test]
+    fn missing_format_or_variant_is_none() {
+        assert_eq!(choose("square", None, Some("avif"), ""), None);
+        assert_eq!(choose("crop", None, None, "image/avif"), None);
+        assert_eq!(
+            choose("square", None, None, "image/avif,image/webp"),
+            chosen("square", "jpeg", 256)
+        );
+    }
+}
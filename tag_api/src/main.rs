@@ -1,10 +1,14 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use actix_cors::Cors;
 use actix_web::{App, HttpServer, middleware::Logger, web};
 use database::SqlDatabase;
 use dotenv::dotenv;
-use endpoints::{find_images, image, imageinfo, root, search_characters, search_tags, thumbnail};
+use endpoints::{
+    block_hash, blocklist, delete_image, find_images, image, imageinfo, root, search_characters,
+    search_tags, set_crop, status, tasks, thumbnail, unblock_hash, upload, upload_status,
+};
+mod color;
 mod database;
 use anyhow::Result;
 use env_logger::Env;
@@ -32,7 +36,7 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin(&config.website_url)
-            .allowed_methods(vec!["GET"]);
+            .allowed_methods(vec!["GET", "POST", "DELETE"]);
         App::new()
             .wrap(Logger::default())
             .wrap(cors)
@@ -44,6 +48,15 @@ async fn main() -> std::io::Result<()> {
             .service(search_characters)
             .service(thumbnail)
             .service(imageinfo)
+            .service(status)
+            .service(set_crop)
+            .service(delete_image)
+            .service(blocklist)
+            .service(block_hash)
+            .service(unblock_hash)
+            .service(tasks)
+            .service(upload)
+            .service(upload_status)
     })
     .bind(address)?
     .run()
@@ -56,8 +69,13 @@ struct Config {
     port: u16,
     database_url: String,
     image_url_prefix: String,
-    image_storage_path: String,
+    storage: storage::StorageConfig,
+    storage_redirect: bool,
+    presign_expiry_secs: u64,
     website_url: String,
+    thumbnail_size: u32,
+    upload_dir: String,
+    upload_max_mb: u64,
 }
 
 fn load_config() -> Result<Config> {
@@ -68,17 +86,46 @@ fn load_config() -> Result<Config> {
             .unwrap_or(8080),
         image_url_prefix: std::env::var("IMAGE_URL_PREFIX")
             .unwrap_or("http://127.0.0.1:8080".to_string()),
-        image_storage_path: std::env::var("STORAGE_DIR").unwrap_or("/Images/Storage".to_string()),
+        storage: storage::StorageConfig::from_env()?,
+        storage_redirect: std::env::var("STORAGE_REDIRECT")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(false),
+        presign_expiry_secs: std::env::var("PRESIGN_EXPIRY_SECS")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(3600),
         database_url: std::env::var("DATABASE_URL")?,
         website_url: std::env::var("WEBSITE_URL")?,
+        thumbnail_size: std::env::var("THUMBNAIL_SIZE")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(600),
+        upload_dir: std::env::var("UPLOAD_DIR").unwrap_or("/Images/Upload".to_string()),
+        upload_max_mb: std::env::var("UPLOAD_MAX_MB")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(200),
     })
 }
 fn load_statics(config: &Config) -> Result<()> {
     endpoints::IMAGE_PREFIX
         .set(config.image_url_prefix.clone())
         .unwrap();
-    database::IMAGE_PATH
-        .set(config.image_storage_path.clone().into())
+    endpoints::DEFAULT_THUMBNAIL_SIZE
+        .set(config.thumbnail_size)
+        .unwrap();
+    endpoints::REDIRECT_EXPIRY
+        .set(
+            config
+                .storage_redirect
+                .then(|| Duration::from_secs(config.presign_expiry_secs)),
+        )
+        .unwrap();
+    endpoints::UPLOAD_DIR
+        .set(config.upload_dir.clone().into())
+        .unwrap();
+    endpoints::UPLOAD_MAX_BYTES
+        .set(config.upload_max_mb << 20)
+        .unwrap();
+    database::STORAGE
+        .set(storage::Storage::new(&config.storage)?)
         .unwrap();
 
     Ok(())
@@ -32,11 +32,69 @@ pub struct ImageRequest {
     pub token: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailRequest {
+    pub token: Option<String>,
+    #[serde(default, deserialize_with = "option_from_str_or_number")]
+    pub size: Option<u32>,
+    pub format: Option<String>,
+    pub variant: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteImageRequest {
+    pub token: Option<String>,
+    /// Block the image's hash so it isn't imported again, true unless set.
+    #[serde(default, deserialize_with = "option_from_str_or_number")]
+    pub block: Option<bool>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlocklistQuery {
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub pages: Paginated,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockHashRequest {
+    /// Average hash as 16 hex digits, as listed by `/admin/blocklist`.
+    pub hash: String,
+    pub reason: String,
+}
+
+/// The optional `sidecar` field of an upload, applied to every file in it.
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadSidecar {
+    /// Where the images come from, e.g. the page they were saved from.
+    pub source: Option<String>,
+    /// General tags added to the tagger's.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Replaces whatever rating the tagger assigns.
+    pub rating: Option<Rating>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CropRequest {
+    pub x: u32,
+    pub y: u32,
+    pub size: Option<u32>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FindImageRequest {
     pub characters: Option<String>,
     pub tags: Option<String>,
     pub rating: Option<Rating>,
+    /// Hex color, e.g. `3366ff`.
+    pub color: Option<String>,
+    /// Maximum CIELAB distance to `color`.
+    #[serde(default, deserialize_with = "option_from_str_or_number")]
+    pub tolerance: Option<f32>,
+    #[serde(default, deserialize_with = "option_from_str_or_number")]
+    pub monochrome: Option<bool>,
     pub token: Option<String>,
     #[serde(flatten)]
     pub pages: Paginated,
@@ -1,24 +1,38 @@
-use std::fmt::format;
 
-use actix_web::{HttpResponse, Responder, body::BoxBody, http::StatusCode};
+use actix_web::{
+    HttpResponse, Responder,
+    body::BoxBody,
+    http::{StatusCode, header},
+};
 use serde::Serialize;
 use serde_json::json;
+use storage::ByteStream;
 
 use crate::database::Rating;
 
 pub struct ApiResponse<T: Serialize, E: Serialize> {
     status: StatusCode,
     data: ApiData<T, E>,
+    headers: Vec<(header::HeaderName, &'static str)>,
 }
 
 pub enum ApiData<T: Serialize, E: Serialize> {
     Json(Result<T, E>),
-    Binary(Vec<u8>, String), // data, content_type
+    Stream(ByteStream, String), // data, content_type
+    Redirect(String),
 }
 
 impl<T: Serialize, E: Serialize> ApiResponse<T, E> {
     pub fn new(status: StatusCode, data: ApiData<T, E>) -> Self {
-        Self { status, data }
+        Self {
+            status,
+            data,
+            headers: Vec::new(),
+        }
+    }
+    pub fn with_header(mut self, name: header::HeaderName, value: &'static str) -> Self {
+        self.headers.push((name, value));
+        self
     }
     pub fn new_json(status: StatusCode, data: Result<T, E>) -> Self {
         Self::new(status, ApiData::Json(data))
@@ -35,8 +49,11 @@ impl<T: Serialize, E: Serialize> ApiResponse<T, E> {
     pub fn new_not_allowed(error: E) -> Self {
         Self::new_json(StatusCode::METHOD_NOT_ALLOWED, Err(error))
     }
-    pub fn new_binary(status: StatusCode, content: Vec<u8>, content_type: &str) -> Self {
-        Self::new(status, ApiData::Binary(content, content_type.to_string()))
+    pub fn new_stream(status: StatusCode, content: ByteStream, content_type: &str) -> Self {
+        Self::new(status, ApiData::Stream(content, content_type.to_string()))
+    }
+    pub fn new_redirect(url: String) -> Self {
+        Self::new(StatusCode::FOUND, ApiData::Redirect(url))
     }
 }
 
@@ -44,19 +61,24 @@ impl<T: Serialize, E: Serialize> Responder for ApiResponse<T, E> {
     type Body = BoxBody;
 
     fn respond_to(self, _: &actix_web::HttpRequest) -> actix_web::HttpResponse<Self::Body> {
+        let mut builder = HttpResponse::build(self.status);
+        for (name, value) in self.headers {
+            builder.insert_header((name, value));
+        }
         match self.data {
             ApiData::Json(ref result) => {
                 let body = match result {
                     Ok(_) => json!({"status": self.status.as_u16() , "data": result}),
                     Err(_) => json!({"status": self.status.as_u16(), "data": result}),
                 };
-                HttpResponse::build(self.status)
+                builder
                     .content_type("application/json")
                     .body(body.to_string())
             }
-            ApiData::Binary(content, content_type) => HttpResponse::build(self.status)
-                .content_type(content_type)
-                .body(content),
+            ApiData::Stream(content, content_type) => {
+                builder.content_type(content_type).streaming(content)
+            }
+            ApiData::Redirect(url) => builder.insert_header((header::LOCATION, url)).finish(),
         }
     }
 }
@@ -117,18 +139,40 @@ pub struct Imagedata {
     id: i32,
     url: String,
     thumbnail_url: String,
+    thumbnails: Vec<ThumbnailSource>,
+    /// BlurHash placeholder to show while the thumbnail loads, once computed.
+    blurhash: Option<String>,
 }
 
 impl Imagedata {
-    pub fn new(id: i32, url: String, thumbnail_url : String) -> Self {
-        Self { id, url, thumbnail_url}
+    pub fn new(
+        id: i32,
+        url: String,
+        thumbnail_url: String,
+        thumbnails: Vec<ThumbnailSource>,
+        blurhash: Option<String>,
+    ) -> Self {
+        Self { id, url, thumbnail_url, thumbnails, blurhash }
     }
 }
 
+/// One entry of an image's `srcset`, `width` being the `w` descriptor.
+#[derive(Debug, Serialize)]
+pub struct ThumbnailSource {
+    pub url: String,
+    pub width: i32,
+    pub format: String,
+    pub variant: String,
+}
+
 pub struct ImageDbInfo{
     pub tags: Vec<String>,
     pub characters: Vec<String>,
-    pub rating: Rating,
+    pub rating: Option<Rating>,
+    pub blurhash: Option<String>,
+    pub palette: Vec<String>,
+    pub grayscale: Option<bool>,
+    pub transparent: Option<bool>,
     pub id: u32,
 }
 
@@ -136,7 +180,12 @@ pub struct ImageDbInfo{
 pub struct ImageInfo{
     pub tags: Vec<String>,
     pub characters: Vec<String>,
-    pub rating: Rating,
+    pub rating: Option<Rating>,
+    pub blurhash: Option<String>,
+    /// Dominant colors as hex, most common first.
+    pub palette: Vec<String>,
+    pub grayscale: Option<bool>,
+    pub transparent: Option<bool>,
     pub image_url: String,
     pub tag_url: String,
 }
@@ -152,3 +201,50 @@ pub struct CharacterData{
     pub name: String,
     pub count: u32
 }
+
+#[derive(Debug, Serialize)]
+pub struct BlockedHashData {
+    pub hash: String,
+    pub reason: String,
+    pub image_id: Option<i32>,
+    pub created_at: i64,
+}
+
+/// The latest run of a tag_manager maintenance task. `finished_at` and
+/// `duration_ms` are unset while it is running.
+#[derive(Debug, Serialize)]
+pub struct TaskRun {
+    pub task: String,
+    pub worker_id: String,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub outcome: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceStatus {
+    pub component: String,
+    pub state: String,
+    pub detail: String,
+    pub updated_at: i64,
+}
+
+/// A file accepted by `/upload`, under the name it was sent with.
+#[derive(Debug, Serialize)]
+pub struct UploadedFile {
+    pub file: String,
+    pub job_id: i32,
+}
+
+/// Progress of an uploaded file. `state` is pending until the file is stored as
+/// `image_id` (done) or discarded for `reason` (rejected).
+#[derive(Debug, Serialize)]
+pub struct UploadStatus {
+    pub job_id: i32,
+    pub state: &'static str,
+    pub stage: String,
+    pub image_id: Option<i32>,
+    pub reason: Option<String>,
+}
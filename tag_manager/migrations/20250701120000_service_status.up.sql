@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE "service_status" (
+  component TEXT NOT NULL PRIMARY KEY,
+  state TEXT NOT NULL,
+  detail TEXT NOT NULL DEFAULT '',
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
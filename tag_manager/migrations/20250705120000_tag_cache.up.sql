@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE "tag_cache" (
+  content_hash BYTEA NOT NULL,
+  model TEXT NOT NULL,
+  tags JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (content_hash, model)
+);
+
+CREATE INDEX tag_cache_model ON tag_cache (model);
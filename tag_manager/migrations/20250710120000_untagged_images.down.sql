@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS image_untagged;
+
+UPDATE "image" SET rating = 'explicit' WHERE rating IS NULL;
+ALTER TABLE "image" ALTER COLUMN rating SET NOT NULL;
+ALTER TABLE "image" DROP COLUMN IF EXISTS tagged;
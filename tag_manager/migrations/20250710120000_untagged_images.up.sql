@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- Images are stored before they are tagged; rating stays NULL until the tag worker has run.
+ALTER TABLE "image" ADD COLUMN tagged BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE "image" ALTER COLUMN tagged SET DEFAULT false;
+ALTER TABLE "image" ALTER COLUMN rating DROP NOT NULL;
+
+CREATE INDEX image_untagged ON "image" (id) WHERE NOT tagged;
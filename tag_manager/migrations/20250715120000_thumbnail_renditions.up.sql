@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE "image" ADD COLUMN IF NOT EXISTS thumbnail BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE "thumbnail" (
+  image_id integer NOT NULL,
+  size integer NOT NULL,
+  format TEXT NOT NULL,
+  width integer NOT NULL,
+  height integer NOT NULL,
+  PRIMARY KEY (image_id, size, format),
+  CONSTRAINT fk_image FOREIGN KEY (image_id) REFERENCES image(id)
+);
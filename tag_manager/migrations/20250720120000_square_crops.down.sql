@@ -0,0 +1,11 @@
+-- Add down migration script here
+DELETE FROM "thumbnail" WHERE variant <> 'fit';
+ALTER TABLE "thumbnail" DROP CONSTRAINT thumbnail_pkey;
+ALTER TABLE "thumbnail" ADD PRIMARY KEY (image_id, size, format);
+ALTER TABLE "thumbnail" DROP COLUMN IF EXISTS variant;
+
+ALTER TABLE "image"
+  DROP COLUMN IF EXISTS crop_x,
+  DROP COLUMN IF EXISTS crop_y,
+  DROP COLUMN IF EXISTS crop_size,
+  DROP COLUMN IF EXISTS crop_override;
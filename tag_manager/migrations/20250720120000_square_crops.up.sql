@@ -0,0 +1,10 @@
+-- Add up migration script here
+ALTER TABLE "image"
+  ADD COLUMN crop_x integer,
+  ADD COLUMN crop_y integer,
+  ADD COLUMN crop_size integer,
+  ADD COLUMN crop_override BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE "thumbnail" ADD COLUMN variant TEXT NOT NULL DEFAULT 'fit';
+ALTER TABLE "thumbnail" DROP CONSTRAINT thumbnail_pkey;
+ALTER TABLE "thumbnail" ADD PRIMARY KEY (image_id, variant, size, format);
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE "image" ADD COLUMN blurhash TEXT;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS image_color;
+ALTER TABLE "image" DROP COLUMN IF EXISTS transparent;
+ALTER TABLE "image" DROP COLUMN IF EXISTS grayscale;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Both stay NULL until the palette has been extracted.
+ALTER TABLE "image" ADD COLUMN grayscale BOOLEAN;
+ALTER TABLE "image" ADD COLUMN transparent BOOLEAN;
+
+CREATE TABLE "image_color" (
+  image_id integer NOT NULL,
+  position smallint NOT NULL,
+  red smallint NOT NULL,
+  green smallint NOT NULL,
+  blue smallint NOT NULL,
+  l real NOT NULL,
+  a real NOT NULL,
+  b real NOT NULL,
+  weight real NOT NULL,
+  PRIMARY KEY (image_id, position),
+  CONSTRAINT fk_image FOREIGN KEY (image_id) REFERENCES image(id)
+);
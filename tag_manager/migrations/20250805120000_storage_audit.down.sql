@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE "image" DROP COLUMN IF EXISTS broken;
+ALTER TABLE "image" DROP COLUMN IF EXISTS sha256;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- SHA-256 of the stored original. Filled in on ingest, or by the first audit for older images.
+ALTER TABLE "image" ADD COLUMN sha256 BYTEA;
+-- Set by the storage audit when the original is missing or fails its checksum.
+ALTER TABLE "image" ADD COLUMN broken BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- One row per imported file, advanced as each stage completes so an interrupted
+-- import resumes where it stopped. Tagging isn't a stage: it runs after ingest
+-- and is tracked by image.tagged.
+CREATE TABLE "ingest_job" (
+  id SERIAL PRIMARY KEY,
+  path TEXT NOT NULL,
+  stage TEXT NOT NULL DEFAULT 'discovered'
+    CHECK (stage IN ('discovered', 'decoded', 'stored', 'thumbnailed', 'done', 'failed')),
+  image_id integer,
+  error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  CONSTRAINT fk_image FOREIGN KEY (image_id) REFERENCES image(id) ON DELETE SET NULL
+);
+
+-- A file has at most one job in progress; finished ones are kept for a while.
+CREATE UNIQUE INDEX ingest_job_active_path ON "ingest_job" (path)
+  WHERE stage NOT IN ('done', 'failed');
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS worker_id;
+DROP TABLE IF EXISTS "ingest_worker";
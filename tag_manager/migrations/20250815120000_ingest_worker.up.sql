@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- Running tag_manager instances. Jobs claimed by one that stopped sending
+-- heartbeats are picked up by the others.
+CREATE TABLE "ingest_worker" (
+  id TEXT PRIMARY KEY,
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  heartbeat_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE "ingest_job" ADD COLUMN worker_id TEXT;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS keep_file;
+ALTER TABLE "image" DROP COLUMN IF EXISTS extra_tags;
+ALTER TABLE "image" DROP COLUMN IF EXISTS min_rating;
+ALTER TABLE "image" DROP COLUMN IF EXISTS forced_rating;
+ALTER TABLE "image" DROP COLUMN IF EXISTS source;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- Defaults of the import root an image came from, applied when it is tagged.
+ALTER TABLE "image" ADD COLUMN source TEXT;
+ALTER TABLE "image" ADD COLUMN forced_rating rating;
+ALTER TABLE "image" ADD COLUMN min_rating rating;
+ALTER TABLE "image" ADD COLUMN extra_tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- Set for files in copy roots, which stay in the import directory. Their finished
+-- job is what keeps them from being imported again, so it is never pruned.
+ALTER TABLE "ingest_job" ADD COLUMN keep_file BOOLEAN NOT NULL DEFAULT false;
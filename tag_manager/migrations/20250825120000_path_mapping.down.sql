@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE "image" DROP COLUMN IF EXISTS extra_characters;
+ALTER TABLE "image" DROP COLUMN IF EXISTS artist;
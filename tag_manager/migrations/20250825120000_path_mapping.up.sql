@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Taken from the import directory structure or the import root's config.
+ALTER TABLE "image" ADD COLUMN artist TEXT;
+ALTER TABLE "image" ADD COLUMN extra_characters TEXT[] NOT NULL DEFAULT '{}';
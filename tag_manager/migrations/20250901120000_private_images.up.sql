@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Set by ingest rules; private images are only shown to admins.
+ALTER TABLE "image" ADD COLUMN private BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- Average hashes of images that must not be imported again. Imports within a
+-- few bits of one are discarded before they are stored.
+CREATE TABLE "blocked_hash" (
+  hash BYTEA PRIMARY KEY,
+  reason TEXT NOT NULL,
+  -- The image the hash was taken from, when it was removed from the library.
+  image_id integer,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS discarded_path;
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS attempts;
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS retryable;
+DROP TABLE IF EXISTS "scheduled_task_run";
@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- One row per run of a scheduled maintenance task, kept for a month.
+CREATE TABLE "scheduled_task_run" (
+  id SERIAL PRIMARY KEY,
+  task TEXT NOT NULL,
+  worker_id TEXT NOT NULL,
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  finished_at TIMESTAMPTZ,
+  outcome TEXT NOT NULL DEFAULT 'running' CHECK (outcome IN ('running', 'ok', 'failed')),
+  detail TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX scheduled_task_run_task ON "scheduled_task_run" (task, started_at DESC);
+
+-- Failed jobs the retry task may run again: those that didn't fail because of the
+-- file itself. A moved file's place in the discard directory is kept to put it back.
+ALTER TABLE "ingest_job" ADD COLUMN retryable BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE "ingest_job" ADD COLUMN attempts integer NOT NULL DEFAULT 0;
+ALTER TABLE "ingest_job" ADD COLUMN discarded_path TEXT;
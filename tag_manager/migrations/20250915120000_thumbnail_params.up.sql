@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- Settings the image's thumbnails were rendered with. Images whose settings differ
+-- from the current ones, including those from before this column, are re-rendered
+-- in the background while their old thumbnails keep being served. This is the one
+-- reset for images stored before renditions and square crops existed, which only
+-- have the legacy JPEG until then.
+ALTER TABLE "image" ADD COLUMN thumbnail_params TEXT;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS uploaded_by;
+ALTER TABLE "ingest_job" DROP COLUMN IF EXISTS defaults;
+ALTER TABLE IF EXISTS "auth" DROP COLUMN IF EXISTS can_upload;
@@ -0,0 +1,16 @@
+-- Add up migration script here
+-- Tokens that may push images through tag_api's /upload. Admin tokens always may.
+-- auth is tag_api's token table and isn't created by these migrations, so it is
+-- only extended where it exists.
+DO $$
+BEGIN
+  IF to_regclass('auth') IS NOT NULL THEN
+    ALTER TABLE "auth" ADD COLUMN IF NOT EXISTS can_upload BOOLEAN NOT NULL DEFAULT false;
+  END IF;
+END $$;
+
+-- Uploaded files bring their own source, tags and rating instead of a root's, in
+-- the shape of an import root's defaults. The token they were uploaded with, as
+-- stored in auth, is the only one that can poll them.
+ALTER TABLE "ingest_job" ADD COLUMN defaults JSONB;
+ALTER TABLE "ingest_job" ADD COLUMN uploaded_by BYTEA;
@@ -0,0 +1,321 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use anyhow::{Result, anyhow};
+use futures::{StreamExt, stream};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::Database,
+    image_path::{original_key, storage, thumbnail_key, to_quarantine},
+};
+
+/// Repairs the audit is allowed to make. Without any it only reports.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AuditOptions {
+    /// Clear the thumbnail flag of images with missing renditions, so the next
+    /// processing run renders them again.
+    pub regenerate_thumbnails: bool,
+    /// Move stored files without a row into `QUARANTINE_DIR`.
+    pub quarantine_orphans: bool,
+    /// Mark rows whose file is missing or fails its checksum as broken, which hides
+    /// them from the API and the workers.
+    pub mark_broken: bool,
+}
+
+impl AuditOptions {
+    /// Parses the flags of `tag_manager audit`.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut options = AuditOptions::default();
+        for arg in args {
+            match arg.as_str() {
+                "--regenerate-thumbnails" => options.regenerate_thumbnails = true,
+                "--quarantine-orphans" => options.quarantine_orphans = true,
+                "--mark-broken" => options.mark_broken = true,
+                "--repair" => {
+                    options = AuditOptions {
+                        regenerate_thumbnails: true,
+                        quarantine_orphans: true,
+                        mark_broken: true,
+                    }
+                }
+                other => return Err(anyhow!("Unknown audit option: {other}")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// One `image` row as far as the audit is concerned.
+pub struct AuditRow {
+    pub id: u32,
+    pub sha256: Option<Vec<u8>>,
+    pub thumbnail: bool,
+    pub broken: bool,
+    /// Its ingest job is still running, so the original may not be stored yet.
+    pub importing: bool,
+}
+
+#[derive(Default)]
+pub struct AuditReport {
+    pub images: usize,
+    pub missing_files: Vec<u32>,
+    pub orphan_files: Vec<String>,
+    pub missing_thumbnails: Vec<u32>,
+    pub checksum_mismatches: Vec<u32>,
+    /// Rows stored before checksums existed; their current hash was recorded.
+    pub checksums_recorded: usize,
+    /// Rows left alone as their import may still be storing the original: those
+    /// with a running ingest job, and those without a checksum or a file.
+    pub skipped: usize,
+    pub repairs: usize,
+}
+
+impl AuditReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.orphan_files.is_empty()
+            && self.missing_thumbnails.is_empty()
+            && self.checksum_mismatches.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} images, {} skipped, {} missing files, {} orphan files, {} missing thumbnails, {} checksum mismatches, {} repairs",
+            self.images,
+            self.skipped,
+            self.missing_files.len(),
+            self.orphan_files.len(),
+            self.missing_thumbnails.len(),
+            self.checksum_mismatches.len(),
+            self.repairs
+        )
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Storage audit: {}", self.summary())?;
+        if self.checksums_recorded > 0 {
+            writeln!(
+                f,
+                "Recorded checksums for {} images",
+                self.checksums_recorded
+            )?;
+        }
+        for id in &self.missing_files {
+            writeln!(f, "missing file: {}", original_key(*id))?;
+        }
+        for key in &self.orphan_files {
+            writeln!(f, "orphan file: {key}")?;
+        }
+        for id in &self.missing_thumbnails {
+            writeln!(f, "missing thumbnails: image {id}")?;
+        }
+        for id in &self.checksum_mismatches {
+            writeln!(f, "checksum mismatch: {}", original_key(*id))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the stored files with the `image` table and verifies every original
+/// against its stored SHA-256.
+pub async fn run(database: &impl Database, options: AuditOptions) -> Result<AuditReport> {
+    // Files are listed before rows are read. Rows are inserted before their file is
+    // written, so an image stored in the meantime can't show up as an orphan.
+    let files = storage().list().await?;
+    let rows = database.get_audit_rows().await?;
+    let renditions = database.get_thumbnail_files().await?;
+    let mut report = AuditReport {
+        images: rows.len(),
+        ..Default::default()
+    };
+
+    let ids: HashSet<u32> = rows.iter().map(|x| x.id).collect();
+    for key in files {
+        if stored_id(&key).is_some_and(|id| !ids.contains(&id)) {
+            report.orphan_files.push(key);
+        }
+    }
+
+    // Rows of running imports still count as known ids above, so their files
+    // aren't orphans, but aren't checked themselves.
+    let audited: Vec<u32> = rows.iter().filter(|x| !x.importing).map(|x| x.id).collect();
+    report.skipped = rows.len() - audited.len();
+
+    // Streams owned ids rather than borrowed rows, which would keep the future from
+    // being `Send` and so from being spawned.
+    let checks: Vec<(u32, Result<Option<[u8; 32]>>)> = stream::iter(audited)
+        .map(|id| async move { (id, checksum(&original_key(id)).await) })
+        .buffer_unordered(4)
+        .collect()
+        .await;
+
+    let rows_by_id: HashMap<u32, &AuditRow> = rows.iter().map(|x| (x.id, x)).collect();
+    for (id, checksum) in checks {
+        let row = rows_by_id[&id];
+        match (checksum?, &row.sha256) {
+            // Checksums are recorded as originals are stored, so without one the
+            // file may just not be written yet.
+            (None, None) => {
+                report.skipped += 1;
+                continue;
+            }
+            (None, Some(_)) => report.missing_files.push(row.id),
+            (Some(checksum), None) => {
+                database.write_checksum(row.id, &checksum).await?;
+                report.checksums_recorded += 1;
+            }
+            (Some(checksum), Some(stored)) if stored.as_slice() != checksum => {
+                report.checksum_mismatches.push(row.id)
+            }
+            (Some(_), Some(_)) => {}
+        }
+
+        let expected = renditions.get(&row.id).filter(|_| row.thumbnail);
+        let mut missing = false;
+        for (variant, size, format) in expected.into_iter().flatten() {
+            if !storage()
+                .exists(&thumbnail_key(row.id, *variant, *size, *format))
+                .await?
+            {
+                missing = true;
+                break;
+            }
+        }
+        if missing {
+            report.missing_thumbnails.push(row.id);
+        }
+    }
+
+    report.missing_files.sort_unstable();
+    report.checksum_mismatches.sort_unstable();
+    report.missing_thumbnails.sort_unstable();
+    report.orphan_files.sort();
+
+    let broken: HashMap<u32, bool> = rows.iter().map(|x| (x.id, x.broken)).collect();
+    if options.mark_broken {
+        for id in report
+            .missing_files
+            .iter()
+            .chain(&report.checksum_mismatches)
+        {
+            if !broken[id] {
+                database.mark_broken(*id).await?;
+                report.repairs += 1;
+            }
+        }
+    }
+    if options.regenerate_thumbnails {
+        for id in &report.missing_thumbnails {
+            if !report.missing_files.contains(id) {
+                database.reset_thumbnail(*id).await?;
+                report.repairs += 1;
+            }
+        }
+    }
+    if options.quarantine_orphans {
+        for key in &report.orphan_files {
+            quarantine(key).await?;
+            report.repairs += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Image id a stored file belongs to, from `{id}.png` or `{id}_thumbnail...`.
+/// Files not named after an id aren't ours and are left alone.
+fn stored_id(key: &str) -> Option<u32> {
+    let end = key.find(['.', '_']).unwrap_or(key.len());
+    key[..end].parse().ok()
+}
+
+/// SHA-256 of a stored file, or `None` if it doesn't exist. Read as a stream, so
+/// the audit holds a few chunks per file rather than whole originals.
+async fn checksum(key: &str) -> Result<Option<[u8; 32]>> {
+    let Some(mut stream) = storage().stream(key).await? else {
+        return Ok(None);
+    };
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?);
+    }
+    Ok(Some(hasher.finalize().into()))
+}
+
+/// Moves a stored file to the local quarantine directory, whichever backend it
+/// was stored in.
+async fn quarantine(key: &str) -> Result<()> {
+    let Some(bytes) = storage().get(key).await? else {
+        return Ok(());
+    };
+    let target = to_quarantine(key);
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(target, bytes).await?;
+    storage().delete(key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn stored_id_of_originals_and_thumbnails() {
+        assert_eq!(stored_id(&original_key(12)), Some(12));
+        assert_eq!(stored_id(&storage::keys::legacy_thumbnail_key(7)), Some(7));
+        assert_eq!(stored_id(&storage::keys::thumbnail_key(3, "fit", 256, "jpeg")), Some(3));
+        assert_eq!(stored_id(&storage::keys::thumbnail_key(3, "square", 128, "avif")), Some(3));
+    }
+
+    #[test]
+    fn stored_id_ignores_foreign_files() {
+        assert_eq!(stored_id(".DS_Store"), None);
+        assert_eq!(stored_id("readme.txt"), None);
+        assert_eq!(stored_id(""), None);
+    }
+
+    #[test]
+    fn no_flags_only_reports() {
+        let options = AuditOptions::from_args(&[]).unwrap();
+        assert!(!options.regenerate_thumbnails);
+        assert!(!options.quarantine_orphans);
+        assert!(!options.mark_broken);
+    }
+
+    #[test]
+    fn flags_enable_single_repairs() {
+        let options = AuditOptions::from_args(&args(&["--quarantine-orphans"])).unwrap();
+        assert!(!options.regenerate_thumbnails);
+        assert!(options.quarantine_orphans);
+        assert!(!options.mark_broken);
+
+        let options =
+            AuditOptions::from_args(&args(&["--regenerate-thumbnails", "--mark-broken"])).unwrap();
+        assert!(options.regenerate_thumbnails);
+        assert!(!options.quarantine_orphans);
+        assert!(options.mark_broken);
+    }
+
+    #[test]
+    fn repair_enables_everything() {
+        let options = AuditOptions::from_args(&args(&["--repair"])).unwrap();
+        assert!(options.regenerate_thumbnails);
+        assert!(options.quarantine_orphans);
+        assert!(options.mark_broken);
+    }
+
+    #[test]
+    fn unknown_flag_is_rejected() {
+        assert!(AuditOptions::from_args(&args(&["--fix"])).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    fmt,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::{sync::watch, time::sleep};
+use tracing::{debug, warn};
+
+use crate::tag_fetcher;
+
+pub static TAGGER: OnceLock<CircuitBreaker> = OnceLock::new();
+
+pub fn tagger() -> &'static CircuitBreaker {
+    TAGGER.get().unwrap()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+/// Tracks tag service availability. Requests are only let through while closed;
+/// after `threshold` consecutive failures the circuit opens and stays open until a
+/// health probe succeeds.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: watch::Sender<BreakerState>,
+    failures: AtomicU32,
+    threshold: u32,
+    probe_interval: Duration,
+}
+
+impl CircuitBreaker {
+    /// Starts open so nothing is sent before the first successful probe.
+    pub fn new(threshold: u32, probe_interval: Duration) -> Self {
+        Self {
+            state: watch::Sender::new(BreakerState::Open),
+            failures: AtomicU32::new(0),
+            threshold: threshold.max(1),
+            probe_interval,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        *self.state.borrow()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state() == BreakerState::Closed
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<BreakerState> {
+        self.state.subscribe()
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.transition(BreakerState::Closed);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.state() != BreakerState::Closed || failures >= self.threshold {
+            self.transition(BreakerState::Open);
+        }
+    }
+
+    /// Resolves once the circuit is closed again.
+    pub async fn closed(&self) {
+        let _ = self
+            .subscribe()
+            .wait_for(|state| *state == BreakerState::Closed)
+            .await;
+    }
+
+    fn transition(&self, to: BreakerState) {
+        let changed = self.state.send_if_modified(|state| {
+            if *state == to {
+                false
+            } else {
+                *state = to;
+                true
+            }
+        });
+        if changed {
+            warn!(
+                state = %to,
+                consecutive_failures = self.consecutive_failures(),
+                "Tag service circuit changed state"
+            );
+        }
+    }
+
+    /// Probes the tag service health endpoint while the circuit is open, with the
+    /// configured interval jittered by +-50% so restarts don't probe in lockstep.
+    pub async fn run_probes(&self) {
+        let mut receiver = self.subscribe();
+        let mut first = true;
+        loop {
+            if receiver
+                .wait_for(|state| *state == BreakerState::Open)
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            if !first {
+                let jitter = rand::thread_rng().gen_range(0.5..1.5);
+                sleep(self.probe_interval.mul_f64(jitter)).await;
+            }
+            first = false;
+
+            self.transition(BreakerState::HalfOpen);
+            match tag_fetcher::check_health().await {
+                Ok(()) => {
+                    tag_fetcher::forget_capabilities();
+                    self.record_success();
+                }
+                Err(e) => {
+                    debug!(error = %e, "Tag service health probe failed");
+                    self.record_failure();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(threshold, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn starts_open_until_a_success() {
+        let breaker = breaker(3);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        breaker.record_success();
+        assert!(breaker.is_closed());
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = breaker(3);
+        breaker.record_success();
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.consecutive_failures(), 3);
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(2);
+        breaker.record_success();
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures(), 1);
+    }
+
+    #[test]
+    fn half_open_closes_on_success() {
+        let breaker = breaker(3);
+        breaker.transition(BreakerState::HalfOpen);
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures(), 0);
+    }
+
+    #[test]
+    fn half_open_reopens_on_a_single_failure() {
+        let breaker = breaker(3);
+        breaker.transition(BreakerState::HalfOpen);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn threshold_is_at_least_one() {
+        let breaker = breaker(0);
+        breaker.record_success();
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[tokio::test]
+    async fn closed_resolves_after_success() {
+        let breaker = breaker(1);
+        let waiter = breaker.closed();
+        breaker.record_success();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("closed() should resolve once the circuit closes");
+    }
+}
@@ -0,0 +1,165 @@
+use image::{DynamicImage, GenericImageView, imageops::FilterType};
+
+/// Longest side of the copy the crop heuristic works on.
+const ANALYSIS_SIZE: u32 = 128;
+
+/// Square region of the stored image, in source pixels, used for square thumbnails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+impl CropRect {
+    /// Keeps a (possibly admin supplied) rectangle inside the image. A missing
+    /// size means the largest square that fits.
+    pub fn clamped(x: u32, y: u32, size: Option<u32>, width: u32, height: u32) -> Self {
+        let size = size.unwrap_or(u32::MAX).min(width).min(height).max(1);
+        Self {
+            x: x.min(width - size),
+            y: y.min(height - size),
+            size,
+        }
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        image.crop_imm(self.x, self.y, self.size, self.size)
+    }
+}
+
+/// Picks the square window with the most edge detail along the image's long axis.
+/// For portrait images windows around the upper third are favoured, since that is
+/// where faces sit in most character art.
+pub fn square_crop(image: &DynamicImage) -> CropRect {
+    let (width, height) = image.dimensions();
+    let size = width.min(height);
+    if width == height || size == 0 {
+        return CropRect { x: 0, y: 0, size };
+    }
+
+    let scale = ANALYSIS_SIZE as f32 / width.max(height) as f32;
+    let small = image
+        .resize_exact(
+            ((width as f32 * scale).round() as u32).max(2),
+            ((height as f32 * scale).round() as u32).max(2),
+            FilterType::Triangle,
+        )
+        .to_luma8();
+    let (small_width, small_height) = small.dimensions();
+    let portrait = height > width;
+
+    // Edge energy summed across the short axis, one value per step along the long axis.
+    let long = if portrait { small_height } else { small_width };
+    let mut profile = vec![0.0f32; long as usize];
+    for y in 0..small_height - 1 {
+        for x in 0..small_width - 1 {
+            let here = small.get_pixel(x, y)[0] as f32;
+            let energy = (small.get_pixel(x + 1, y)[0] as f32 - here).abs()
+                + (small.get_pixel(x, y + 1)[0] as f32 - here).abs();
+            profile[if portrait { y } else { x } as usize] += energy;
+        }
+    }
+
+    let mut prefix = vec![0.0f32; profile.len() + 1];
+    for (index, value) in profile.iter().enumerate() {
+        prefix[index + 1] = prefix[index] + value;
+    }
+
+    let window = (if portrait { small_width } else { small_height }).min(long) as usize;
+    let best = (0..=profile.len() - window)
+        .map(|start| {
+            let center = (start as f32 + window as f32 / 2.0) / long as f32;
+            let bias = if portrait {
+                1.0 - 0.5 * (center - 1.0 / 3.0).abs()
+            } else {
+                1.0 - 0.25 * (center - 0.5).abs()
+            };
+            (start, (prefix[start + window] - prefix[start]) * bias)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(start, _)| start);
+
+    let offset = ((best as f32 / scale).round() as u32).min(width.max(height) - size);
+    if portrait {
+        CropRect { x: 0, y: offset, size }
+    } else {
+        CropRect { x: offset, y: 0, size }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    /// Flat grey image with a black and white checkerboard over the given columns.
+    fn with_detail(width: u32, height: u32, columns: std::ops::Range<u32>) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            if columns.contains(&x) {
+                if (x / 4 + y / 4) % 2 == 0 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) }
+            } else {
+                Rgb([128, 128, 128])
+            }
+        }))
+    }
+
+    #[test]
+    fn clamped_defaults_to_largest_square() {
+        assert_eq!(
+            CropRect::clamped(0, 0, None, 300, 200),
+            CropRect { x: 0, y: 0, size: 200 }
+        );
+    }
+
+    #[test]
+    fn clamped_keeps_rect_inside_image() {
+        assert_eq!(
+            CropRect::clamped(250, 190, Some(100), 300, 200),
+            CropRect { x: 200, y: 100, size: 100 }
+        );
+        assert_eq!(
+            CropRect::clamped(10, 10, Some(500), 300, 200),
+            CropRect { x: 10, y: 0, size: 200 }
+        );
+    }
+
+    #[test]
+    fn clamped_size_is_never_zero() {
+        assert_eq!(CropRect::clamped(0, 0, Some(0), 10, 10).size, 1);
+    }
+
+    #[test]
+    fn square_image_is_kept_whole() {
+        let image = with_detail(64, 64, 0..64);
+        assert_eq!(square_crop(&image), CropRect { x: 0, y: 0, size: 64 });
+    }
+
+    #[test]
+    fn landscape_crop_follows_detail() {
+        let image = with_detail(400, 100, 300..400);
+        let rect = square_crop(&image);
+        assert_eq!(rect.y, 0);
+        assert_eq!(rect.size, 100);
+        assert!(rect.x >= 250, "crop at {} misses the detailed edge", rect.x);
+        assert!(rect.x + rect.size <= 400);
+    }
+
+    #[test]
+    fn evenly_detailed_portrait_favours_upper_third() {
+        let image = with_detail(100, 400, 0..100);
+        let rect = square_crop(&image);
+        assert_eq!(rect.x, 0);
+        assert_eq!(rect.size, 100);
+        let center = rect.y + rect.size / 2;
+        assert!((100..=170).contains(&center), "crop centred at {center}");
+    }
+
+    #[test]
+    fn crop_applies_to_the_rect() {
+        let image = with_detail(300, 200, 0..0);
+        let cropped = CropRect { x: 50, y: 20, size: 120 }.apply(&image);
+        assert_eq!(cropped.dimensions(), (120, 120));
+    }
+}
@@ -1,19 +1,106 @@
+use std::{collections::HashMap, path::Path};
+
 use anyhow::Result;
+use sqlx::{Connection, Row, postgres::PgPoolOptions, types::Json};
+use tracing::warn;
 
 use crate::{
     Config,
+    audit::AuditRow,
     tag_fetcher::{Rating, Tags},
+    crop::CropRect,
+    import_root::ImportDefaults,
+    ingest::{IngestJob, RetryableJob, Stage},
+    palette::Palette,
+    processor::{IMPORT_CONCURRENCY, TAG_CONCURRENCY},
+    scheduler::Statistics,
+    thumbnail::{self, Rendition, ThumbnailFormat, Variant},
 };
 
 pub trait Database {
-    async fn create(config: &Config) -> Result<impl Database + Clone>;
-    async fn save_image(&self, hash: &[u8; 8], tags: &Tags) -> Result<u32>;
-    async fn check_hash(&self, hash: &[u8; 8]) -> Result<bool>;
+    async fn create(config: &Config) -> Result<Self>
+    where
+        Self: Sized;
+    async fn save_image(&self, job: u32, hash: &[u8; 8], defaults: &ImportDefaults) -> Result<Option<u32>>;
+    async fn get_import_defaults(&self, id: u32) -> Result<ImportDefaults>;
+    async fn delete_image(&self, id: u32) -> Result<()>;
+    async fn get_blocked_reason(&self, hash: &[u8; 8]) -> Result<Option<String>>;
+    async fn block_image(&self, id: u32, reason: &str) -> Result<()>;
+    async fn write_tags(&self, id: u32, tags: &Tags, private: bool) -> Result<()>;
     fn config(&self) -> &Config;
     async fn get_non_thumbnailed_images(&self) -> Result<Vec<u32>>;
-    async fn write_thumbnail(&self, id: u32) -> Result<()>;
+    async fn get_untagged_images(&self) -> Result<Vec<u32>>;
+    async fn write_thumbnails(&self, id: u32, renditions: &[Rendition], crop: CropRect) -> Result<()>;
+    async fn get_crop_override(&self, id: u32) -> Result<Option<(u32, u32, Option<u32>)>>;
+    async fn write_blurhash(&self, id: u32, blurhash: &str) -> Result<()>;
+    async fn get_images_without_blurhash(&self) -> Result<Vec<u32>>;
+    async fn write_palette(&self, id: u32, palette: &Palette) -> Result<()>;
+    async fn get_images_without_palette(&self) -> Result<Vec<u32>>;
+    async fn write_checksum(&self, id: u32, sha256: &[u8; 32]) -> Result<()>;
+    async fn get_audit_rows(&self) -> Result<Vec<AuditRow>>;
+    async fn get_thumbnail_files(&self) -> Result<HashMap<u32, Vec<(Variant, u32, ThumbnailFormat)>>>;
+    async fn get_renditions(&self, id: u32) -> Result<Vec<(Variant, u32, ThumbnailFormat)>>;
+    async fn mark_broken(&self, id: u32) -> Result<()>;
+    async fn reset_thumbnail(&self, id: u32) -> Result<()>;
+    async fn set_status(&self, component: &str, state: &str, detail: &str) -> Result<()>;
+    async fn get_cached_tags(&self, content_hash: &[u8; 32], model: &str) -> Result<Option<Tags>>;
+    async fn cache_tags(&self, content_hash: &[u8; 32], model: &str, tags: &Tags) -> Result<()>;
+    async fn purge_tag_cache(&self, current_model: &str) -> Result<u64>;
+    async fn discover_job(&self, path: &Path, keep_file: bool) -> Result<bool>;
+    async fn claim_jobs(&self, limit: usize) -> Result<Vec<IngestJob>>;
+    async fn count_pending_jobs(&self) -> Result<u64>;
+    async fn advance_job(&self, job: u32, stage: Stage) -> Result<()>;
+    async fn fail_job(&self, job: u32, error: &str, retryable: bool, discarded_path: Option<&Path>) -> Result<()>;
+    async fn get_retryable_jobs(&self, max_attempts: u32) -> Result<Vec<RetryableJob>>;
+    async fn retry_job(&self, job: u32) -> Result<bool>;
+    async fn abandon_job(&self, job: u32) -> Result<()>;
+    async fn prune_jobs(&self) -> Result<u64>;
+    async fn heartbeat(&self) -> Result<()>;
+    async fn deregister_worker(&self) -> Result<()>;
+    async fn try_exclusive(&self, name: &str) -> Result<Option<ExclusiveLock>>;
+    async fn last_task_run(&self, task: &str) -> Result<Option<i64>>;
+    async fn start_task_run(&self, task: &str) -> Result<u32>;
+    async fn finish_task_run(&self, run: u32, outcome: &str, detail: &str) -> Result<()>;
+    async fn get_stale_thumbnail_images(&self) -> Result<Vec<u32>>;
+    async fn refresh_statistics(&self) -> Result<Statistics>;
+}
+
+/// Held while doing work only one worker should do at a time. Backed by a
+/// session-level advisory lock on a connection of its own, so neither a pooled
+/// connection nor an open transaction is tied up for the length of the work.
+/// Unlocked when this is dropped; should the connection be lost instead, the
+/// session ends and Postgres releases the lock with it.
+pub struct ExclusiveLock {
+    connection: Option<sqlx::PgConnection>,
+    name: String,
 }
 
+impl Drop for ExclusiveLock {
+    fn drop(&mut self) {
+        // Without a runtime the connection is just dropped, which ends the session.
+        let (Some(mut connection), Ok(runtime)) =
+            (self.connection.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let name = std::mem::take(&mut self.name);
+        runtime.spawn(async move {
+            if let Err(e) = sqlx::query("SELECT pg_advisory_unlock(hashtext($1))")
+                .bind(&name)
+                .execute(&mut connection)
+                .await
+            {
+                warn!(lock = name, error = %e, "Could not release exclusive lock");
+            }
+            let _ = connection.close().await;
+        });
+    }
+}
+
+/// Connections kept by one worker: one per concurrent import and tagging task,
+/// plus room for the scheduler, heartbeats and status reports running meanwhile.
+const MAX_CONNECTIONS: u32 = (IMPORT_CONCURRENCY + TAG_CONCURRENCY) as u32 + 6;
+
 #[derive(Clone)]
 pub struct SqlDatabase {
     pool: sqlx::postgres::PgPool,
@@ -21,59 +108,193 @@ pub struct SqlDatabase {
 }
 
 impl Database for SqlDatabase {
-    async fn create(config: &Config) -> Result<impl Database + Clone> {
+    async fn create(config: &Config) -> Result<Self> {
         Ok(Self {
-            pool: sqlx::postgres::PgPool::connect(&config.connection_string).await?,
+            pool: PgPoolOptions::new()
+                .max_connections(MAX_CONNECTIONS)
+                .connect(&config.connection_string)
+                .await?,
             config: config.clone(),
         })
     }
 
-    async fn check_hash(&self, hash: &[u8; 8]) -> Result<bool> {
-        let query: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM image WHERE hash=$1)")
+    /// Inserts the image and moves its job to `decoded` together, so a crash can't
+    /// leave a row the job doesn't know about, which would be rejected as a duplicate.
+    /// Returns `None` if an image with the same hash already exists.
+    async fn save_image(&self, job: u32, hash: &[u8; 8], defaults: &ImportDefaults) -> Result<Option<u32>> {
+        let mut tx = self.pool.begin().await?;
+        // Workers importing copies of the same picture would otherwise both pass
+        // the duplicate check before either inserts.
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(i64::from_be_bytes(*hash))
+            .execute(&mut *tx)
+            .await?;
+        let exists: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM image WHERE hash=$1)")
             .bind(hash)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
             .await?;
+        if exists.0 {
+            return Ok(None);
+        }
+
+        let rec: (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO image (hash, source, forced_rating, min_rating, extra_tags, extra_characters, artist)
+            VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id
+            "#,
+        )
+        .bind(hash)
+        .bind(&defaults.source)
+        .bind(&defaults.rating)
+        .bind(&defaults.min_rating)
+        .bind(&defaults.tags)
+        .bind(&defaults.characters)
+        .bind(&defaults.artist)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE ingest_job SET stage = 'decoded', image_id = $2, updated_at = now() WHERE id = $1",
+            job as i32,
+            rec.0
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
 
-        Ok(query.0)
+        Ok(Some(rec.0 as u32))
     }
-    async fn save_image(&self, hash: &[u8; 8], tags: &Tags) -> Result<u32> {
-        let rec: (i32,) =
-            sqlx::query_as("INSERT INTO image (rating, hash) VALUES ($1, $2) RETURNING id")
-                .bind(tags.rating.clone() as Rating)
-                .bind(hash)
-                .fetch_one(&self.pool)
+
+    async fn get_import_defaults(&self, id: u32) -> Result<ImportDefaults> {
+        let row = sqlx::query(
+            r#"
+            SELECT source, forced_rating, min_rating, extra_tags, extra_characters, artist
+            FROM image WHERE id = $1
+            "#,
+        )
+        .bind(id as i32)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(ImportDefaults {
+            rating: row.try_get("forced_rating")?,
+            min_rating: row.try_get("min_rating")?,
+            tags: row.try_get("extra_tags")?,
+            characters: row.try_get("extra_characters")?,
+            artist: row.try_get("artist")?,
+            source: row.try_get("source")?,
+        })
+    }
+
+    /// Removes an image whose import didn't finish, with everything written for it.
+    async fn delete_image(&self, id: u32) -> Result<()> {
+        let id = id as i32;
+        let mut tx = self.pool.begin().await?;
+        for table in ["thumbnail", "image_color", "tag_images", "character_images"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE image_id = $1"))
+                .bind(id)
+                .execute(&mut *tx)
                 .await?;
+        }
+        sqlx::query!("DELETE FROM image WHERE id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
 
-        let id = rec.0;
+    /// Why the closest blocked hash within `blocklist_distance` bits of `hash` was
+    /// blocked, if there is one.
+    async fn get_blocked_reason(&self, hash: &[u8; 8]) -> Result<Option<String>> {
+        let reason: Option<(String,)> = sqlx::query_as(
+            r#"
+            SELECT reason FROM (
+                SELECT reason, length(replace(
+                    (('x' || encode(hash, 'hex'))::bit(64) # ('x' || encode($1, 'hex'))::bit(64))::text,
+                    '0', ''
+                )) AS distance
+                FROM blocked_hash
+            ) b
+            WHERE distance <= $2
+            ORDER BY distance
+            LIMIT 1
+            "#,
+        )
+        .bind(hash)
+        .bind(self.config.blocklist_distance as i32)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(reason.map(|x| x.0))
+    }
+
+    /// Blocks the hash of a stored image. Call before deleting it.
+    async fn block_image(&self, id: u32, reason: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO blocked_hash (hash, reason, image_id)
+            SELECT hash, $2, id FROM image WHERE id = $1
+            ON CONFLICT (hash) DO NOTHING
+            "#,
+            id as i32,
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn write_tags(&self, id: u32, tags: &Tags, private: bool) -> Result<()> {
+        let id = id as i32;
+        let mut tx = self.pool.begin().await?;
+
+        // Clear anything left over from an earlier, interrupted attempt.
+        sqlx::query!("DELETE FROM character_images WHERE image_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM tag_images WHERE image_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
 
+        // The tag ids are looked up on the transaction's own connection, as workers
+        // each waiting on a second pooled connection could starve the pool. New tags
+        // stay locked until commit, so they are created in a fixed order to keep
+        // concurrent writers from deadlocking.
         if let Some(character_tags) = &tags.character_tags {
-            for tag in character_tags {
-                let tag_id = self.get_character_tag_id(tag).await?;
+            for tag in sorted_unique(character_tags) {
+                let tag_id = Self::get_character_tag_id(&mut tx, tag).await?;
 
                 sqlx::query!(
                     "INSERT INTO character_images (image_id, character_id) VALUES ($1, $2)",
                     id,
                     tag_id
                 )
-                .execute(&self.pool)
+                .execute(&mut *tx)
                 .await?;
             }
         }
         if let Some(general_tags) = &tags.general_tags {
-            for tag in general_tags {
-                let tag_id = self.get_general_tag_id(tag).await?;
+            for tag in sorted_unique(general_tags) {
+                let tag_id = Self::get_general_tag_id(&mut tx, tag).await?;
 
                 sqlx::query!(
                     "INSERT INTO tag_images (image_id, tag_id) VALUES ($1, $2)",
                     id,
                     tag_id
                 )
-                .execute(&self.pool)
+                .execute(&mut *tx)
                 .await?;
             }
         }
 
-        Ok(rec.0 as u32)
+        sqlx::query("UPDATE image SET rating = $1, tagged = true, private = $3 WHERE id = $2")
+            .bind(tags.rating.clone() as Rating)
+            .bind(id)
+            .bind(private)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
     }
 
     fn config(&self) -> &Config {
@@ -81,7 +302,64 @@ impl Database for SqlDatabase {
     }
 
     async fn get_non_thumbnailed_images(&self) -> Result<Vec<u32>> {
-        Ok(sqlx::query!("SELECT id from image where thumbnail=false")
+        Ok(sqlx::query!("SELECT id from image where thumbnail=false AND NOT broken")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|x| x.id as u32)
+            .collect())
+    }
+
+    async fn get_untagged_images(&self) -> Result<Vec<u32>> {
+        Ok(sqlx::query!("SELECT id from image where tagged=false AND NOT broken ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|x| x.id as u32)
+            .collect())
+    }
+
+    async fn write_thumbnails(&self, id: u32, renditions: &[Rendition], crop: CropRect) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM thumbnail WHERE image_id=$1", id as i32)
+            .execute(&mut *tx)
+            .await?;
+        for rendition in renditions {
+            sqlx::query!(
+                "INSERT INTO thumbnail (image_id, variant, size, format, width, height) VALUES ($1, $2, $3, $4, $5, $6)",
+                id as i32,
+                rendition.variant.to_string(),
+                rendition.size as i32,
+                rendition.format.to_string(),
+                rendition.width as i32,
+                rendition.height as i32
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "UPDATE image SET thumbnail=true, crop_x=$2, crop_y=$3, crop_size=$4, thumbnail_params=$5 WHERE id=$1;",
+            id as i64,
+            crop.x as i32,
+            crop.y as i32,
+            crop.size as i32,
+            self.thumbnail_params()
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn write_blurhash(&self, id: u32, blurhash: &str) -> Result<()> {
+        sqlx::query!("UPDATE image SET blurhash=$2 WHERE id=$1", id as i32, blurhash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_images_without_blurhash(&self) -> Result<Vec<u32>> {
+        Ok(sqlx::query!("SELECT id FROM image WHERE blurhash IS NULL AND thumbnail LIMIT 500")
             .fetch_all(&self.pool)
             .await?
             .iter()
@@ -89,14 +367,500 @@ impl Database for SqlDatabase {
             .collect())
     }
 
-    async fn write_thumbnail(&self, id: u32) -> Result<()> {
-        sqlx::query!("UPDATE image SET thumbnail=true WHERE id=$1;", id as i64).execute(&self.pool).await?;
+    async fn write_palette(&self, id: u32, palette: &Palette) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM image_color WHERE image_id=$1", id as i32)
+            .execute(&mut *tx)
+            .await?;
+        for (position, color) in palette.colors.iter().enumerate() {
+            sqlx::query!(
+                r#"
+                INSERT INTO image_color (image_id, position, red, green, blue, l, a, b, weight)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                "#,
+                id as i32,
+                position as i16,
+                color.rgb[0] as i16,
+                color.rgb[1] as i16,
+                color.rgb[2] as i16,
+                color.lab[0],
+                color.lab[1],
+                color.lab[2],
+                color.weight
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "UPDATE image SET grayscale=$2, transparent=$3 WHERE id=$1",
+            id as i32,
+            palette.grayscale,
+            palette.transparent
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_images_without_palette(&self) -> Result<Vec<u32>> {
+        Ok(sqlx::query!("SELECT id FROM image WHERE grayscale IS NULL AND thumbnail LIMIT 500")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|x| x.id as u32)
+            .collect())
+    }
+
+    async fn write_checksum(&self, id: u32, sha256: &[u8; 32]) -> Result<()> {
+        sqlx::query!("UPDATE image SET sha256=$2 WHERE id=$1", id as i32, sha256.as_slice())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_audit_rows(&self) -> Result<Vec<AuditRow>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT id, sha256, thumbnail, broken, EXISTS (
+                SELECT 1 FROM ingest_job j
+                WHERE j.image_id = image.id AND j.stage NOT IN ('done', 'failed')
+            ) AS "importing!"
+            FROM image ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| AuditRow {
+            id: x.id as u32,
+            sha256: x.sha256,
+            thumbnail: x.thumbnail,
+            broken: x.broken,
+            importing: x.importing,
+        })
+        .collect())
+    }
+
+    async fn get_thumbnail_files(&self) -> Result<HashMap<u32, Vec<(Variant, u32, ThumbnailFormat)>>> {
+        let mut files: HashMap<u32, Vec<_>> = HashMap::new();
+        for record in sqlx::query!("SELECT image_id, variant, size, format FROM thumbnail")
+            .fetch_all(&self.pool)
+            .await?
+        {
+            files.entry(record.image_id as u32).or_default().push((
+                record.variant.parse()?,
+                record.size as u32,
+                record.format.parse()?,
+            ));
+        }
+        Ok(files)
+    }
+
+    async fn get_renditions(&self, id: u32) -> Result<Vec<(Variant, u32, ThumbnailFormat)>> {
+        sqlx::query!("SELECT variant, size, format FROM thumbnail WHERE image_id=$1", id as i32)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|x| Ok((x.variant.parse()?, x.size as u32, x.format.parse()?)))
+            .collect()
+    }
+
+    async fn mark_broken(&self, id: u32) -> Result<()> {
+        sqlx::query!("UPDATE image SET broken=true WHERE id=$1", id as i32)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn reset_thumbnail(&self, id: u32) -> Result<()> {
+        sqlx::query!("UPDATE image SET thumbnail=false WHERE id=$1", id as i32)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_crop_override(&self, id: u32) -> Result<Option<(u32, u32, Option<u32>)>> {
+        let record = sqlx::query!(
+            "SELECT crop_x, crop_y, crop_size FROM image WHERE id=$1 AND crop_override",
+            id as i32
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record.map(|x| {
+            (
+                x.crop_x.unwrap_or(0) as u32,
+                x.crop_y.unwrap_or(0) as u32,
+                x.crop_size.map(|size| size as u32),
+            )
+        }))
+    }
+
+    async fn set_status(&self, component: &str, state: &str, detail: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO service_status (component, state, detail, updated_at)
+            VALUES ($1, $2, $3, now())
+            ON CONFLICT (component) DO UPDATE
+            SET state = EXCLUDED.state, detail = EXCLUDED.detail, updated_at = EXCLUDED.updated_at
+            "#,
+            component,
+            state,
+            detail
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_cached_tags(&self, content_hash: &[u8; 32], model: &str) -> Result<Option<Tags>> {
+        let record: Option<(Json<Tags>,)> =
+            sqlx::query_as("SELECT tags FROM tag_cache WHERE content_hash=$1 AND model=$2")
+                .bind(content_hash.as_slice())
+                .bind(model)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(record.map(|x| x.0.0))
+    }
+
+    async fn cache_tags(&self, content_hash: &[u8; 32], model: &str, tags: &Tags) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO tag_cache (content_hash, model, tags) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(content_hash.as_slice())
+        .bind(model)
+        .bind(Json(tags))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn purge_tag_cache(&self, current_model: &str) -> Result<u64> {
+        Ok(sqlx::query!("DELETE FROM tag_cache WHERE model <> $1", current_model)
+            .execute(&self.pool)
+            .await?
+            .rows_affected())
+    }
+
+    /// Queues `path` unless it already has a job in progress. With `keep_file` the
+    /// file stays where it is after import, so any earlier job for it counts.
+    /// Returns whether it was new.
+    async fn discover_job(&self, path: &Path, keep_file: bool) -> Result<bool> {
+        let path = path.to_string_lossy();
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO ingest_job (path, keep_file)
+            SELECT $1, $2
+            WHERE NOT ($2 AND EXISTS (SELECT 1 FROM ingest_job WHERE path = $1))
+            ON CONFLICT (path) WHERE stage NOT IN ('done', 'failed') DO NOTHING
+            "#,
+            path.as_ref(),
+            keep_file
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(inserted > 0)
+    }
+
+    /// Claims up to `limit` unfinished jobs for this worker: unclaimed ones, and
+    /// ones held by a worker whose last heartbeat is older than the worker timeout.
+    /// Rows another worker is claiming at the same moment are skipped rather than
+    /// waited on.
+    async fn claim_jobs(&self, limit: usize) -> Result<Vec<IngestJob>> {
+        sqlx::query!(
+            r#"
+            UPDATE ingest_job SET worker_id = $1, updated_at = now()
+            WHERE id IN (
+                SELECT j.id FROM ingest_job j
+                WHERE j.stage NOT IN ('done', 'failed')
+                AND (
+                    j.worker_id IS NULL
+                    OR j.worker_id = $1
+                    OR NOT EXISTS (
+                        SELECT 1 FROM ingest_worker w
+                        WHERE w.id = j.worker_id
+                        AND w.heartbeat_at > now() - make_interval(secs => $3)
+                    )
+                )
+                ORDER BY j.id
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, path, stage, image_id, defaults as "defaults: Json<ImportDefaults>"
+            "#,
+            self.config.worker_id,
+            limit as i64,
+            self.config.worker_timeout_secs as f64
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| {
+            Ok(IngestJob {
+                id: x.id as u32,
+                path: x.path.into(),
+                stage: x.stage.parse()?,
+                image_id: x.image_id.map(|x| x as u32),
+                defaults: x.defaults.map(|x| x.0),
+            })
+        })
+        .collect()
+    }
+
+    async fn count_pending_jobs(&self) -> Result<u64> {
+        let count: (i64,) = sqlx::query_as(
+            "SELECT count(*) FROM ingest_job WHERE stage NOT IN ('done', 'failed')",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count.0 as u64)
+    }
+
+    async fn advance_job(&self, job: u32, stage: Stage) -> Result<()> {
+        sqlx::query!(
+            "UPDATE ingest_job SET stage = $2, updated_at = now() WHERE id = $1",
+            job as i32,
+            stage.to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn fail_job(&self, job: u32, error: &str, retryable: bool, discarded_path: Option<&Path>) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE ingest_job
+            SET stage = 'failed', error = $2, retryable = $3, discarded_path = $4, updated_at = now()
+            WHERE id = $1
+            "#,
+            job as i32,
+            error,
+            retryable,
+            discarded_path.map(|x| x.to_string_lossy().into_owned())
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_retryable_jobs(&self, max_attempts: u32) -> Result<Vec<RetryableJob>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT id, path, discarded_path FROM ingest_job
+            WHERE stage = 'failed' AND retryable AND attempts < $1
+            ORDER BY id
+            "#,
+            max_attempts as i32
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| RetryableJob {
+            id: x.id as u32,
+            path: x.path.into(),
+            discarded_path: x.discarded_path.map(Into::into),
+        })
+        .collect())
+    }
+
+    /// Queues a failed job again from the start. `false` if its file has another
+    /// job in progress, which then imports it instead.
+    async fn retry_job(&self, job: u32) -> Result<bool> {
+        let updated = sqlx::query!(
+            r#"
+            UPDATE ingest_job j
+            SET stage = 'discovered', attempts = attempts + 1, error = NULL, image_id = NULL,
+                worker_id = NULL, discarded_path = NULL, updated_at = now()
+            WHERE id = $1
+            AND NOT EXISTS (
+                SELECT 1 FROM ingest_job a
+                WHERE a.path = j.path AND a.stage NOT IN ('done', 'failed')
+            )
+            "#,
+            job as i32
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Stops retrying a job, for one whose file is gone.
+    async fn abandon_job(&self, job: u32) -> Result<()> {
+        sqlx::query!(
+            "UPDATE ingest_job SET retryable = false, updated_at = now() WHERE id = $1",
+            job as i32
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Drops finished jobs after a month; they are only kept for troubleshooting.
+    /// Jobs of kept files are what stops them from being imported again.
+    async fn prune_jobs(&self) -> Result<u64> {
+        Ok(sqlx::query!(
+            "DELETE FROM ingest_job WHERE stage IN ('done', 'failed') AND NOT keep_file AND updated_at < now() - interval '30 days'"
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected())
+    }
+
+    async fn heartbeat(&self) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO ingest_worker (id) VALUES ($1)
+            ON CONFLICT (id) DO UPDATE SET heartbeat_at = now()
+            "#,
+            self.config.worker_id
+        )
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
+
+    /// Hands back the jobs this worker claimed but didn't start, on shutdown.
+    async fn deregister_worker(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "UPDATE ingest_job SET worker_id = NULL WHERE worker_id = $1 AND stage NOT IN ('done', 'failed')",
+            self.config.worker_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM ingest_worker WHERE id = $1", self.config.worker_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// `None` if another worker holds the lock named `name`.
+    async fn try_exclusive(&self, name: &str) -> Result<Option<ExclusiveLock>> {
+        let mut connection = sqlx::PgConnection::connect(&self.config.connection_string).await?;
+        let locked: (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock(hashtext($1))")
+            .bind(name)
+            .fetch_one(&mut connection)
+            .await?;
+        if !locked.0 {
+            connection.close().await?;
+            return Ok(None);
+        }
+        Ok(Some(ExclusiveLock {
+            connection: Some(connection),
+            name: name.to_string(),
+        }))
+    }
+
+    /// When the last run of `task` started, as a unix timestamp.
+    async fn last_task_run(&self, task: &str) -> Result<Option<i64>> {
+        let started: (Option<i64>,) = sqlx::query_as(
+            "SELECT extract(epoch FROM max(started_at))::bigint FROM scheduled_task_run WHERE task = $1",
+        )
+        .bind(task)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(started.0)
+    }
+
+    async fn start_task_run(&self, task: &str) -> Result<u32> {
+        let record = sqlx::query!(
+            "INSERT INTO scheduled_task_run (task, worker_id) VALUES ($1, $2) RETURNING id",
+            task,
+            self.config.worker_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record.id as u32)
+    }
+
+    /// Records how a run ended and drops runs older than a month.
+    async fn finish_task_run(&self, run: u32, outcome: &str, detail: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "UPDATE scheduled_task_run SET finished_at = now(), outcome = $2, detail = $3 WHERE id = $1",
+            run as i32,
+            outcome,
+            detail
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM scheduled_task_run WHERE started_at < now() - interval '30 days'")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Thumbnailed images rendered with other settings than the current ones.
+    async fn get_stale_thumbnail_images(&self) -> Result<Vec<u32>> {
+        let ids: Vec<(i32,)> = sqlx::query_as(
+            r#"
+            SELECT id FROM image
+            WHERE thumbnail AND NOT broken AND thumbnail_params IS DISTINCT FROM $1
+            ORDER BY id
+            "#,
+        )
+        .bind(self.thumbnail_params())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().map(|x| x.0 as u32).collect())
+    }
+
+    /// Updates the planner statistics of the busiest tables and counts images and
+    /// jobs.
+    async fn refresh_statistics(&self) -> Result<Statistics> {
+        sqlx::query("ANALYZE image, thumbnail, tag_images, character_images, ingest_job")
+            .execute(&self.pool)
+            .await?;
+        let images: (i64, i64, i64, i64) = sqlx::query_as(
+            r#"
+            SELECT count(*),
+                count(*) FILTER (WHERE NOT tagged),
+                count(*) FILTER (WHERE private),
+                count(*) FILTER (WHERE broken)
+            FROM image
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let jobs: (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT count(*) FILTER (WHERE stage NOT IN ('done', 'failed')),
+                count(*) FILTER (WHERE stage = 'failed')
+            FROM ingest_job
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Statistics {
+            images: images.0 as u64,
+            untagged: images.1 as u64,
+            private: images.2 as u64,
+            broken: images.3 as u64,
+            pending_jobs: jobs.0 as u64,
+            failed_jobs: jobs.1 as u64,
+        })
+    }
 }
 
 impl SqlDatabase {
-    async fn get_character_tag_id(&self, character_name: &str) -> Result<i32> {
+    fn thumbnail_params(&self) -> String {
+        thumbnail::params(
+            &self.config.thumbnail_sizes,
+            &self.config.thumbnail_formats,
+            self.config.thumbnail_quality,
+            self.config.thumbnail_background,
+        )
+    }
+
+    async fn get_character_tag_id(conn: &mut sqlx::PgConnection, character_name: &str) -> Result<i32> {
         let record = sqlx::query!(
             r#"
             WITH ins AS (
@@ -112,12 +876,19 @@ impl SqlDatabase {
             "#,
             character_name
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&mut *conn)
         .await?;
+        if let Some(id) = record.and_then(|x| x.id) {
+            return Ok(id);
+        }
 
-        Ok(record.id.expect("what"))
+        // Another transaction inserted it after this statement's snapshot was taken.
+        let record = sqlx::query!(r#"SELECT id FROM "character" WHERE character = $1"#, character_name)
+            .fetch_one(conn)
+            .await?;
+        Ok(record.id)
     }
-    async fn get_general_tag_id(&self, tag: &str) -> Result<i32> {
+    async fn get_general_tag_id(conn: &mut sqlx::PgConnection, tag: &str) -> Result<i32> {
         let record = sqlx::query!(
             r#"
             WITH ins AS (
@@ -133,9 +904,23 @@ impl SqlDatabase {
             "#,
             tag
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&mut *conn)
         .await?;
+        if let Some(id) = record.and_then(|x| x.id) {
+            return Ok(id);
+        }
 
-        Ok(record.id.expect("what"))
+        // Another transaction inserted it after this statement's snapshot was taken.
+        let record = sqlx::query!(r#"SELECT id FROM "tag" WHERE tag = $1"#, tag)
+            .fetch_one(conn)
+            .await?;
+        Ok(record.id)
     }
 }
+
+fn sorted_unique(tags: &[String]) -> Vec<&str> {
+    let mut tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    tags.sort_unstable();
+    tags.dedup();
+    tags
+}
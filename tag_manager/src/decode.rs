@@ -0,0 +1,273 @@
+use std::{io::Cursor, path::PathBuf, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, codecs::jpeg::JpegDecoder};
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC, png::Png};
+
+use crate::{
+    image_path::storage,
+    memory::{MemoryPermit, memory_budget},
+};
+
+/// Bytes per pixel reserved for a decode: the source pixels, kept for storing,
+/// plus an RGBA8 copy each for color conversion and orientation. PNG and TIFF
+/// can be 16-bit, which doubles the source.
+const DECODE_BYTES_PER_PIXEL: u64 = 12;
+const DECODE_BYTES_PER_PIXEL_16_BIT: u64 = 16;
+
+/// A decoded image and its share of the memory budget, held until dropped. The
+/// pixels are shared between the stages that read them instead of cloned.
+pub struct Decoded {
+    /// Oriented and converted to sRGB: what thumbnails, placeholders, palettes,
+    /// hashes and tags are made from.
+    pub image: Arc<DynamicImage>,
+    /// What is stored as the original.
+    pub source: Source,
+    _memory: MemoryPermit,
+}
+
+/// The pixels as the file has them, at their bit depth, with what it takes to
+/// display them correctly. Shares its pixels with `Decoded::image` when the file
+/// needs neither conversion nor rotation.
+#[derive(Clone)]
+pub struct Source {
+    pub pixels: Arc<DynamicImage>,
+    pub icc_profile: Option<Bytes>,
+    /// EXIF orientation, 1 to 8.
+    pub orientation: Option<u32>,
+}
+
+impl Source {
+    /// Encodes the original as PNG, with the ICC profile and an EXIF block holding
+    /// only the orientation. The rest of the EXIF, such as GPS, isn't kept.
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.pixels
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)?;
+        let orientation = self.orientation.filter(|x| *x != 1);
+        if self.icc_profile.is_none() && orientation.is_none() {
+            return Ok(bytes);
+        }
+
+        let mut png = Png::from_bytes(bytes.into())?;
+        png.set_icc_profile(self.icc_profile.clone());
+        png.set_exif(orientation.map(orientation_exif));
+        Ok(png.encoder().bytes().to_vec())
+    }
+}
+
+/// Reads and decodes an image. Thumbnails and everything else derived from it
+/// are made from a copy with the EXIF orientation applied and converted to sRGB
+/// using the embedded ICC profile, while the source pixels are stored unchanged.
+pub async fn decode_image(path: PathBuf) -> Result<Decoded> {
+    decode_budgeted(Bytes::from(tokio::fs::read(&path).await?)).await
+}
+
+/// Decodes an original or thumbnail from storage. Originals carry their profile
+/// and orientation, which are applied the same way as on import.
+pub async fn decode_stored(key: String) -> Result<Decoded> {
+    decode_budgeted(Bytes::from(get_stored(&key).await?)).await
+}
+
+/// Decodes a stored JPEG at the smallest scale (1/2, 1/4 or 1/8) that is still at
+/// least `min_size` on both sides, which skips most of the work of a full decode.
+/// Only meant for JPEG renditions, which already have orientation and color
+/// conversion applied; originals are PNG and always decoded in full.
+pub async fn decode_stored_scaled(key: String, min_size: u32) -> Result<DynamicImage> {
+    let bytes = get_stored(&key).await?;
+    tokio::task::spawn_blocking(move || {
+        let mut decoder = JpegDecoder::new(Cursor::new(&bytes[..]))?;
+        let size = min_size.min(u16::MAX.into()) as u16;
+        decoder.scale(size, size)?;
+        Ok(DynamicImage::from_decoder(decoder)?)
+    })
+    .await?
+}
+
+async fn get_stored(key: &str) -> Result<Vec<u8>> {
+    storage()
+        .get(key)
+        .await?
+        .ok_or_else(|| anyhow!("{key} is missing from storage"))
+}
+
+/// Waits for the memory the decoded pixels will take, which the header tells
+/// before anything is decoded, then decodes.
+async fn decode_budgeted(bytes: Bytes) -> Result<Decoded> {
+    let reader = image::io::Reader::new(Cursor::new(&bytes[..])).with_guessed_format()?;
+    let bytes_per_pixel = match reader.format() {
+        Some(ImageFormat::Png | ImageFormat::Tiff) => DECODE_BYTES_PER_PIXEL_16_BIT,
+        _ => DECODE_BYTES_PER_PIXEL,
+    };
+    let (width, height) = reader.into_dimensions()?;
+    let memory = memory_budget()
+        .reserve(u64::from(width) * u64::from(height) * bytes_per_pixel)
+        .await;
+    let (source, image) = tokio::task::spawn_blocking(move || decode(bytes)).await??;
+    Ok(Decoded {
+        image,
+        source,
+        _memory: memory,
+    })
+}
+
+/// Decodes the source pixels and derives the sRGB, upright copy from them.
+pub fn decode(bytes: Bytes) -> Result<(Source, Arc<DynamicImage>)> {
+    let mut reader = image::io::Reader::new(Cursor::new(&bytes[..])).with_guessed_format()?;
+    reader.no_limits();
+    let pixels = Arc::new(reader.decode()?);
+
+    let metadata = DynImage::from_bytes(bytes).ok().flatten();
+    let source = Source {
+        pixels: pixels.clone(),
+        icc_profile: metadata.as_ref().and_then(|x| x.icc_profile()),
+        orientation: metadata
+            .as_ref()
+            .and_then(|x| x.exif())
+            .and_then(|x| exif_orientation(x.to_vec())),
+    };
+
+    let converted = source
+        .icc_profile
+        .as_ref()
+        .and_then(|profile| to_srgb(&pixels, profile));
+    let image = match (converted, source.orientation) {
+        (Some(image), Some(orientation)) => Arc::new(apply_orientation(image, orientation)),
+        (Some(image), None) => Arc::new(image),
+        (None, Some(orientation @ 2..=8)) => {
+            Arc::new(apply_orientation((*pixels).clone(), orientation))
+        }
+        (None, _) => pixels,
+    };
+    Ok((source, image))
+}
+
+/// A minimal big-endian EXIF (TIFF) block with a single IFD entry: the orientation.
+fn orientation_exif(orientation: u32) -> Bytes {
+    let mut exif = Vec::with_capacity(26);
+    exif.extend_from_slice(b"MM\0\x2a");
+    exif.extend_from_slice(&8u32.to_be_bytes());
+    exif.extend_from_slice(&1u16.to_be_bytes());
+    // Tag 0x0112, type SHORT, one value, stored left-justified in the entry.
+    exif.extend_from_slice(&0x0112u16.to_be_bytes());
+    exif.extend_from_slice(&3u16.to_be_bytes());
+    exif.extend_from_slice(&1u32.to_be_bytes());
+    exif.extend_from_slice(&(orientation as u16).to_be_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    // No further IFDs.
+    exif.extend_from_slice(&0u32.to_be_bytes());
+    Bytes::from(exif)
+}
+
+fn exif_orientation(exif: Vec<u8>) -> Option<u32> {
+    exif::Reader::new()
+        .read_raw(exif)
+        .ok()?
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Converts pixels from the embedded profile to sRGB, as a new 8-bit image.
+/// Profiles qcms can't use for RGB data (grayscale, CMYK, broken) give `None`,
+/// leaving the pixels as they are.
+fn to_srgb(image: &DynamicImage, profile: &[u8]) -> Option<DynamicImage> {
+    let source = qcms::Profile::new_from_slice(profile, false)?;
+    let target = qcms::Profile::new_sRGB();
+
+    let has_alpha = image.color().has_alpha();
+    let data_type = if has_alpha {
+        qcms::DataType::RGBA8
+    } else {
+        qcms::DataType::RGB8
+    };
+    let transform = qcms::Transform::new(&source, &target, data_type, qcms::Intent::Perceptual)?;
+
+    Some(if has_alpha {
+        let mut pixels = image.to_rgba8();
+        transform.apply(&mut pixels);
+        DynamicImage::ImageRgba8(pixels)
+    } else {
+        let mut pixels = image.to_rgb8();
+        transform.apply(&mut pixels);
+        DynamicImage::ImageRgb8(pixels)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, RgbImage};
+
+    use super::*;
+
+    fn source(image: DynamicImage, icc_profile: Option<&[u8]>, orientation: Option<u32>) -> Source {
+        Source {
+            pixels: Arc::new(image),
+            icc_profile: icc_profile.map(Bytes::copy_from_slice),
+            orientation,
+        }
+    }
+
+    #[test]
+    fn orientation_exif_reads_back() {
+        for orientation in 1..=8 {
+            assert_eq!(
+                exif_orientation(orientation_exif(orientation).to_vec()),
+                Some(orientation)
+            );
+        }
+    }
+
+    #[test]
+    fn stored_original_keeps_pixels_and_orientation() {
+        let pixels =
+            DynamicImage::ImageRgb8(RgbImage::from_fn(4, 2, |x, _| Rgb([x as u8 * 60, 0, 0])));
+        let png = source(pixels.clone(), None, Some(6)).to_png().unwrap();
+
+        let (stored, image) = decode(Bytes::from(png)).unwrap();
+        assert_eq!(stored.orientation, Some(6));
+        assert_eq!(*stored.pixels, pixels);
+        // Rotated for display only.
+        assert_eq!((image.width(), image.height()), (2, 4));
+    }
+
+    #[test]
+    fn stored_original_keeps_bit_depth_and_profile() {
+        let pixels =
+            DynamicImage::ImageRgb16(ImageBuffer::from_pixel(3, 3, Rgb([1000u16, 40000, 65535])));
+        // Not a profile qcms can use, so the pixels are left as they are.
+        let profile = b"not really an icc profile";
+        let png = source(pixels.clone(), Some(profile), None)
+            .to_png()
+            .unwrap();
+
+        let (stored, image) = decode(Bytes::from(png)).unwrap();
+        assert_eq!(*stored.pixels, pixels);
+        assert_eq!(stored.icc_profile.as_deref(), Some(&profile[..]));
+        assert!(Arc::ptr_eq(&stored.pixels, &image));
+    }
+
+    #[test]
+    fn plain_image_is_stored_as_is() {
+        let pixels = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([10, 20, 30])));
+        let png = source(pixels.clone(), None, Some(1)).to_png().unwrap();
+
+        let (stored, image) = decode(Bytes::from(png)).unwrap();
+        assert_eq!(stored.orientation, None);
+        assert!(stored.icc_profile.is_none());
+        assert_eq!(*image, pixels);
+    }
+}
@@ -0,0 +1,92 @@
+use std::{path::PathBuf, sync::OnceLock};
+
+use metrics::gauge;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::telemetry;
+
+pub static DISK_GUARD: OnceLock<DiskGuard> = OnceLock::new();
+
+pub fn disk_guard() -> &'static DiskGuard {
+    DISK_GUARD.get().unwrap()
+}
+
+/// A local directory ingestion writes to, named for logs, status and metrics.
+#[derive(Clone, Debug)]
+pub struct Volume {
+    pub name: &'static str,
+    pub path: PathBuf,
+}
+
+/// Pauses ingestion while any volume it writes to has less than `min_free` bytes
+/// available, so a full disk can't fail a save halfway. Ingestion resumes on the
+/// first check that finds enough space again.
+#[derive(Debug)]
+pub struct DiskGuard {
+    volumes: Vec<Volume>,
+    min_free: u64,
+    /// Why ingestion is paused, `None` while it isn't.
+    paused: watch::Sender<Option<String>>,
+}
+
+impl DiskGuard {
+    pub fn new(volumes: Vec<Volume>, min_free: u64) -> Self {
+        Self {
+            volumes,
+            min_free,
+            paused: watch::Sender::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.paused.subscribe()
+    }
+
+    /// Measures every volume and returns whether there is room to ingest. A volume
+    /// that can't be measured, such as a directory not created yet, doesn't pause
+    /// ingestion on its own.
+    pub fn check(&self) -> bool {
+        let mut low = Vec::new();
+        for volume in &self.volumes {
+            match fs2::available_space(&volume.path) {
+                Ok(free) => {
+                    gauge!(telemetry::DISK_FREE_BYTES, "volume" => volume.name).set(free as f64);
+                    if free < self.min_free {
+                        low.push(format!("{} has {} MiB free", volume.name, free >> 20));
+                    }
+                }
+                Err(e) => {
+                    warn!(volume = volume.name, path = %volume.path.display(), error = %e, "Could not measure free space");
+                }
+            }
+        }
+
+        let reason = (!low.is_empty())
+            .then(|| format!("{}, {} MiB required", low.join(", "), self.min_free >> 20));
+        let ok = reason.is_none();
+        gauge!(telemetry::INGEST_PAUSED).set(if ok { 0.0 } else { 1.0 });
+        self.transition(reason);
+        ok
+    }
+
+    /// Only pausing and resuming are logged, a changed reason is just published.
+    fn transition(&self, to: Option<String>) {
+        let was_paused = self.paused.borrow().is_some();
+        self.paused.send_if_modified(|paused| {
+            if *paused == to {
+                false
+            } else {
+                *paused = to.clone();
+                true
+            }
+        });
+        match to {
+            Some(reason) if !was_paused => {
+                warn!(reason, "Pausing ingestion, not enough free space")
+            }
+            None if was_paused => info!("Resuming ingestion, free space recovered"),
+            _ => {}
+        }
+    }
+}
@@ -1,26 +1,36 @@
 use std::{path::PathBuf, sync::OnceLock};
+use storage::{Storage, keys};
 use uuid::Uuid;
 
+use crate::thumbnail::{ThumbnailFormat, Variant};
+
+pub use storage::keys::original_key;
+
 pub static DISCARD_PATH : OnceLock<PathBuf> = OnceLock::new();
-pub static IMPORT_PATH : OnceLock<PathBuf> = OnceLock::new();
-pub static STORAGE_PATH : OnceLock<PathBuf> = OnceLock::new();
 pub static VIDEO_PATH : OnceLock<PathBuf> = OnceLock::new();
+pub static QUARANTINE_PATH : OnceLock<PathBuf> = OnceLock::new();
+pub static STORAGE : OnceLock<Storage> = OnceLock::new();
+
+/// Originals and thumbnails, on local disk or in an object store.
+pub fn storage() -> &'static Storage {
+    STORAGE.get().unwrap()
+}
 
 pub fn to_discarded() -> PathBuf {
     DISCARD_PATH.get().unwrap().join(Uuid::new_v4().to_string())
         .with_extension("png")
 }
 
-pub fn to_storage(id: u32) -> PathBuf {
-    STORAGE_PATH.get().unwrap().join(id.to_string()).with_extension("png")
-}
-
 pub fn to_video(extension: &str) -> PathBuf {
     VIDEO_PATH.get().unwrap()
         .join(Uuid::new_v4().to_string())
         .with_extension(extension)
 }
 
-pub fn to_storage_thumbnail(id: u32) -> PathBuf{
-    STORAGE_PATH.get().unwrap().join(format!("{id}_thumbnail")).with_extension("jpg")
+pub fn to_quarantine(key: &str) -> PathBuf {
+    QUARANTINE_PATH.get().unwrap().join(key)
+}
+
+pub fn thumbnail_key(id: u32, variant: Variant, size: u32, format: ThumbnailFormat) -> String {
+    keys::thumbnail_key(id, &variant.to_string(), size, &format.to_string())
 }
@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::tag_fetcher::{Rating, Tags};
+
+/// Whether imported files are removed from the import directory. Files in a
+/// `Copy` root are left in place and remembered by their finished ingest job.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Move,
+    Copy,
+}
+
+/// What a directory level below a root stands for, see `ImportRoot::path_mapping`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathField {
+    Artist,
+    Character,
+    Tag,
+    Ignore,
+}
+
+/// Applied to every image imported from a root. Kept on the image row, as tagging
+/// happens after ingest and the config may have changed by then.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ImportDefaults {
+    /// Replaces whatever rating the tagger assigns.
+    pub rating: Option<Rating>,
+    /// Raises lower ratings to this one.
+    pub min_rating: Option<Rating>,
+    /// General tags added to the tagger's.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Character tags added to the tagger's.
+    #[serde(default)]
+    pub characters: Vec<String>,
+    pub artist: Option<String>,
+    /// Where the images come from, e.g. "pixiv".
+    pub source: Option<String>,
+}
+
+impl ImportDefaults {
+    pub fn apply(&self, tags: &mut Tags) {
+        if let Some(rating) = &self.rating {
+            tags.rating = rating.clone();
+        } else if let Some(min_rating) = &self.min_rating
+            && tags.rating < *min_rating
+        {
+            tags.rating = min_rating.clone();
+        }
+
+        add_missing(&mut tags.general_tags, &self.tags);
+        add_missing(&mut tags.character_tags, &self.characters);
+    }
+}
+
+fn add_missing(tags: &mut Option<Vec<String>>, extra: &[String]) {
+    if extra.is_empty() {
+        return;
+    }
+    let tags = tags.get_or_insert_with(Vec::new);
+    for tag in extra {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+}
+
+/// A watched import directory, one `[[import]]` table of the config file.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ImportRoot {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub mode: ImportMode,
+    /// Also import files in subdirectories, other than those of nested roots.
+    #[serde(default)]
+    pub recursive: bool,
+    /// Meaning of the directories between the root and a file, outermost first.
+    /// `["artist", "ignore", "character"]` imports `Artist/Series/Character/1.png`
+    /// with that artist and character. Deeper levels are ignored.
+    #[serde(default)]
+    pub path_mapping: Vec<PathField>,
+    #[serde(flatten)]
+    pub defaults: ImportDefaults,
+}
+
+impl ImportRoot {
+    /// The root's defaults plus whatever `path_mapping` takes from the directories
+    /// `path` is in.
+    pub fn defaults_for(&self, path: &Path) -> ImportDefaults {
+        let mut defaults = self.defaults.clone();
+        let Some(directories) = path
+            .parent()
+            .and_then(|x| x.strip_prefix(&self.path).ok())
+        else {
+            return defaults;
+        };
+
+        for (field, component) in self.path_mapping.iter().zip(directories.components()) {
+            let name = component.as_os_str().to_string_lossy().trim().to_string();
+            if name.is_empty() {
+                continue;
+            }
+            match field {
+                PathField::Artist => defaults.artist = Some(name),
+                PathField::Character => add_missing_one(&mut defaults.characters, tag_name(&name)),
+                PathField::Tag => add_missing_one(&mut defaults.tags, tag_name(&name)),
+                PathField::Ignore => {}
+            }
+        }
+        defaults
+    }
+}
+
+fn add_missing_one(tags: &mut Vec<String>, tag: String) {
+    if !tags.contains(&tag) {
+        tags.push(tag);
+    }
+}
+
+/// Folder names are written for people, tags the way the tagger writes them:
+/// `Hatsune Miku` becomes `hatsune_miku`.
+fn tag_name(name: &str) -> String {
+    name.to_lowercase().split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    import: Vec<ImportRoot>,
+}
+
+/// Reads the `[[import]]` roots from the TOML file at `path`. A missing file is
+/// the same as one without roots.
+pub fn load(path: &Path) -> Result<Vec<ImportRoot>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let file: ConfigFile =
+        toml::from_str(&contents).with_context(|| format!("Invalid {}", path.display()))?;
+    Ok(file.import)
+}
+
+/// The root `path` was found in. Roots may be nested, so the deepest one wins.
+pub fn find<'a>(roots: &'a [ImportRoot], path: &Path) -> Option<&'a ImportRoot> {
+    roots
+        .iter()
+        .filter(|x| path.starts_with(&x.path))
+        .max_by_key(|x| x.path.components().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots(config: &str) -> Vec<ImportRoot> {
+        toml::from_str::<ConfigFile>(config).unwrap().import
+    }
+
+    fn root(mapping: &[PathField]) -> ImportRoot {
+        ImportRoot {
+            path: PathBuf::from("/import/art"),
+            path_mapping: mapping.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn tags(rating: Rating) -> Tags {
+        Tags {
+            rating,
+            character_tags: None,
+            general_tags: Some(vec!["smile".to_string()]),
+        }
+    }
+
+    #[test]
+    fn parses_config() {
+        let roots = roots(
+            r#"
+            [[import]]
+            path = "/import/art"
+            mode = "copy"
+            recursive = true
+            path_mapping = ["artist", "ignore", "character"]
+            min_rating = "sensitive"
+            tags = ["scan"]
+            "#,
+        );
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].mode, ImportMode::Copy);
+        assert!(roots[0].recursive);
+        assert_eq!(
+            roots[0].path_mapping,
+            [PathField::Artist, PathField::Ignore, PathField::Character]
+        );
+        assert_eq!(roots[0].defaults.min_rating, Some(Rating::Sensitive));
+        assert_eq!(roots[0].defaults.tags, ["scan"]);
+    }
+
+    #[test]
+    fn path_mapping_reads_directories() {
+        let root = root(&[PathField::Artist, PathField::Ignore, PathField::Character]);
+        let defaults = root.defaults_for(Path::new("/import/art/Someone/Series/Hatsune Miku/1.png"));
+        assert_eq!(defaults.artist.as_deref(), Some("Someone"));
+        assert_eq!(defaults.characters, ["hatsune_miku"]);
+        assert!(defaults.tags.is_empty());
+    }
+
+    #[test]
+    fn path_mapping_ignores_deeper_levels() {
+        let root = root(&[PathField::Tag]);
+        let defaults = root.defaults_for(Path::new("/import/art/Landscape/Night/1.png"));
+        assert_eq!(defaults.tags, ["landscape"]);
+    }
+
+    #[test]
+    fn path_mapping_keeps_root_defaults() {
+        let mut root = root(&[PathField::Tag]);
+        root.defaults.tags = vec!["landscape".to_string()];
+        root.defaults.artist = Some("Default".to_string());
+
+        let defaults = root.defaults_for(Path::new("/import/art/Landscape/1.png"));
+        assert_eq!(defaults.tags, ["landscape"]);
+        assert_eq!(defaults.artist.as_deref(), Some("Default"));
+
+        let defaults = root.defaults_for(Path::new("/import/art/1.png"));
+        assert_eq!(defaults.tags, ["landscape"]);
+    }
+
+    #[test]
+    fn find_prefers_the_deepest_root() {
+        let roots = roots(
+            r#"
+            [[import]]
+            path = "/import"
+
+            [[import]]
+            path = "/import/art"
+            "#,
+        );
+        let found = |path: &str| find(&roots, Path::new(path)).map(|x| x.path.clone());
+        assert_eq!(found("/import/art/1.png"), Some(PathBuf::from("/import/art")));
+        assert_eq!(found("/import/other/1.png"), Some(PathBuf::from("/import")));
+        assert_eq!(found("/elsewhere/1.png"), None);
+    }
+
+    #[test]
+    fn rating_replaces_and_min_rating_raises() {
+        let defaults = ImportDefaults {
+            rating: Some(Rating::General),
+            ..Default::default()
+        };
+        let mut result = tags(Rating::Explicit);
+        defaults.apply(&mut result);
+        assert_eq!(result.rating, Rating::General);
+
+        let defaults = ImportDefaults {
+            min_rating: Some(Rating::Questionable),
+            ..Default::default()
+        };
+        let mut result = tags(Rating::Sensitive);
+        defaults.apply(&mut result);
+        assert_eq!(result.rating, Rating::Questionable);
+        let mut result = tags(Rating::Explicit);
+        defaults.apply(&mut result);
+        assert_eq!(result.rating, Rating::Explicit);
+    }
+
+    #[test]
+    fn apply_adds_missing_tags_once() {
+        let defaults = ImportDefaults {
+            tags: vec!["smile".to_string(), "scan".to_string()],
+            characters: vec!["hatsune_miku".to_string()],
+            ..Default::default()
+        };
+        let mut result = tags(Rating::General);
+        defaults.apply(&mut result);
+        assert_eq!(result.general_tags.unwrap(), ["smile", "scan"]);
+        assert_eq!(result.character_tags.unwrap(), ["hatsune_miku"]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::{Result, anyhow};
+
+use crate::import_root::ImportDefaults;
+
+/// Progress of an imported file, in order. Each stage is recorded once its work
+/// is durable, so after a crash the import redoes at most the stage it was in.
+/// Tagging happens after ingest and is tracked by the image's `tagged` flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Discovered,
+    /// Hashed and the `image` row inserted.
+    Decoded,
+    /// Original written to storage with its checksum.
+    Stored,
+    Thumbnailed,
+    /// Import file removed, unless its root copies files.
+    Done,
+    Failed,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Discovered => write!(f, "discovered"),
+            Stage::Decoded => write!(f, "decoded"),
+            Stage::Stored => write!(f, "stored"),
+            Stage::Thumbnailed => write!(f, "thumbnailed"),
+            Stage::Done => write!(f, "done"),
+            Stage::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "discovered" => Ok(Stage::Discovered),
+            "decoded" => Ok(Stage::Decoded),
+            "stored" => Ok(Stage::Stored),
+            "thumbnailed" => Ok(Stage::Thumbnailed),
+            "done" => Ok(Stage::Done),
+            "failed" => Ok(Stage::Failed),
+            other => Err(anyhow!("Unknown ingest stage: {other}")),
+        }
+    }
+}
+
+/// An `ingest_job` row. `image_id` is set from `Decoded` on. `defaults` is set
+/// for files uploaded through tag_api, and used instead of their root's.
+#[derive(Clone, Debug)]
+pub struct IngestJob {
+    pub id: u32,
+    pub path: PathBuf,
+    pub stage: Stage,
+    pub image_id: Option<u32>,
+    pub defaults: Option<ImportDefaults>,
+}
+
+/// A failed job the retry task may run again. `discarded_path` is where its
+/// file was moved, unset if its root copies files.
+#[derive(Clone, Debug)]
+pub struct RetryableJob {
+    pub id: u32,
+    pub path: PathBuf,
+    pub discarded_path: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAGES: [Stage; 6] = [
+        Stage::Discovered,
+        Stage::Decoded,
+        Stage::Stored,
+        Stage::Thumbnailed,
+        Stage::Done,
+        Stage::Failed,
+    ];
+
+    #[test]
+    fn stages_round_trip() {
+        for stage in STAGES {
+            assert_eq!(stage.to_string().parse::<Stage>().unwrap(), stage);
+        }
+    }
+
+    #[test]
+    fn stages_match_the_column_check() {
+        let migration = include_str!("../migrations/20250810120000_ingest_job.up.sql");
+        for stage in STAGES {
+            assert!(
+                migration.contains(&format!("'{stage}'")),
+                "{stage} is not allowed by ingest_job.stage"
+            );
+        }
+    }
+
+    #[test]
+    fn stages_are_ordered() {
+        assert!(STAGES.is_sorted());
+    }
+
+    #[test]
+    fn unknown_stage_is_rejected() {
+        assert!("tagged".parse::<Stage>().is_err());
+        assert!("Done".parse::<Stage>().is_err());
+    }
+}
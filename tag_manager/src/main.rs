@@ -1,50 +1,328 @@
-use std::{collections::HashMap, env, path::PathBuf, str::FromStr, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use crate::database::Database;
+mod audit;
+mod circuit_breaker;
+mod crop;
 mod database;
+mod decode;
+mod disk_guard;
 use database::SqlDatabase;
 use dotenv::dotenv;
-use processor::process_images;
-use tokio::time::sleep;
+use processor::{process_images, tag_pending_images};
+use thumbnail::ThumbnailFormat;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 mod image_path;
+mod import_root;
+mod ingest;
+mod memory;
+mod palette;
 mod processor;
+mod rules;
+mod scheduler;
 mod tag_fetcher;
+mod telemetry;
+mod thumbnail;
 
 #[tokio::main]
-async fn main() -> !{
+async fn main() {
     let _ = dotenv();
-    println!("Running tagManager, with thumbnail processing");
 
     let config = Config::create();
+    init_logging(&config.log_format);
+    info!("Running tagManager, with thumbnail processing");
+    if env::var("THUMBNAIL_SIZES").is_err() && env::var("THUMBNAIL_SIZE").is_ok() {
+        warn!(
+            sizes = ?config.thumbnail_sizes,
+            "THUMBNAIL_SIZE is deprecated for choosing rendered sizes, set THUMBNAIL_SIZES instead"
+        );
+    }
     set_static_vars(&config);
 
-    let database = SqlDatabase::create(&config).await.unwrap();
+    let database = connect(&config).await;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|x| x == "audit") {
+        std::process::exit(run_audit_command(&database, &args[1..]).await);
+    }
+
+    if let Some(address) = config.metrics_address {
+        telemetry::install(address).expect("Could not start metrics listener");
+        tokio::spawn(telemetry::report_storage_usage(Duration::from_secs(600)));
+    }
+    tokio::spawn(circuit_breaker::tagger().run_probes());
+    tokio::spawn(report_tagger_status(database.clone()));
+    tokio::spawn(report_disk_status(database.clone()));
+    let shutdown = CancellationToken::new();
+    tokio::spawn(wait_for_signal(shutdown.clone()));
+    tokio::spawn(run_heartbeat(database.clone(), config.worker_timeout_secs));
+    let tag_worker = tokio::spawn(run_tag_worker(database.clone(), shutdown.clone()));
+    let scheduler = tokio::spawn(scheduler::run(database.clone(), shutdown.clone()));
+
+    run_import_loop(&database, &shutdown).await;
+    if let Err(e) = tag_worker.await {
+        error!(error = ?e, "Tag worker panicked");
+    }
+    if let Err(e) = scheduler.await {
+        error!(error = ?e, "Scheduler panicked");
+    }
+    if let Err(e) = database.deregister_worker().await {
+        warn!(error = %e, "Could not release claimed ingest jobs");
+    }
+    info!("Shut down cleanly");
+}
 
+/// Delay before retry `attempt` (starting at 0) of something that failed for a
+/// reason that may go away, such as the database restarting.
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(5u64.saturating_mul(1 << attempt.min(6)).min(300))
+}
+
+async fn connect(config: &Config) -> SqlDatabase {
+    let mut attempt = 0;
     loop {
-        process_images(&database).await.unwrap();
-        sleep(Duration::new(120, 0)).await;
+        match SqlDatabase::create(config).await {
+            Ok(database) => return database,
+            Err(e) => {
+                let delay = backoff(attempt);
+                warn!(error = %e, retry_in_secs = delay.as_secs(), "Could not connect to the database");
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
     }
 }
 
+/// Cancels `shutdown` on SIGTERM or SIGINT so in-flight imports can finish. A
+/// second signal exits right away.
+async fn wait_for_signal(shutdown: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("Shutting down, finishing in-flight imports");
+    shutdown.cancel();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    warn!("Received a second signal, exiting without waiting");
+    std::process::exit(130);
+}
+
+/// Imports new files until shutdown. Failures, usually the database or storage
+/// being unreachable, are retried with backoff instead of taking the process down.
+async fn run_import_loop(database: &SqlDatabase, shutdown: &CancellationToken) {
+    let mut failures = 0;
+    while !shutdown.is_cancelled() {
+        let delay = match process_images(database, shutdown).await {
+            Ok(()) => {
+                failures = 0;
+                Duration::new(120, 0)
+            }
+            Err(e) => {
+                let delay = backoff(failures);
+                error!(error = ?e, retry_in_secs = delay.as_secs(), "Processing images failed");
+                failures += 1;
+                delay
+            }
+        };
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+}
+
+/// Marks this worker alive, several times per timeout so one missed beat doesn't
+/// get its jobs taken over.
+async fn run_heartbeat(database: SqlDatabase, timeout_secs: u64) {
+    let interval = Duration::from_secs((timeout_secs / 4).max(1));
+    loop {
+        if let Err(e) = database.heartbeat().await {
+            warn!(error = %e, "Could not send worker heartbeat");
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Tags images that ingestion stored as untagged. While the tag service circuit
+/// is open this waits for it to close instead of polling on the normal interval.
+async fn run_tag_worker(database: SqlDatabase, shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        if let Err(e) = tag_pending_images(&database, &shutdown).await {
+            error!(error = ?e, "Tagging pending images failed");
+        }
+        let delay = if circuit_breaker::tagger().is_closed() { 30 } else { 120 };
+        tokio::select! {
+            _ = sleep(Duration::new(delay, 0)) => {}
+            _ = circuit_breaker::tagger().closed(), if delay == 120 => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+}
+
+async fn report_tagger_status(database: SqlDatabase) {
+    let mut receiver = circuit_breaker::tagger().subscribe();
+    loop {
+        let state = *receiver.borrow_and_update();
+        let detail = format!(
+            "{} consecutive failures",
+            circuit_breaker::tagger().consecutive_failures()
+        );
+        if let Err(e) = database.set_status("tagger", &state.to_string(), &detail).await {
+            warn!(error = %e, "Could not write tagger status");
+        }
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Published as the "disk" service status whenever ingestion pauses, resumes or
+/// the reason for a pause changes.
+async fn report_disk_status(database: SqlDatabase) {
+    let mut receiver = disk_guard::disk_guard().subscribe();
+    loop {
+        let paused = receiver.borrow_and_update().clone();
+        let (state, detail) = match paused {
+            Some(reason) => ("paused", reason),
+            None => ("ok", "Enough free space".to_string()),
+        };
+        if let Err(e) = database.set_status("disk", state, &detail).await {
+            warn!(error = %e, "Could not write disk status");
+        }
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// `tag_manager audit [--regenerate-thumbnails] [--quarantine-orphans] [--mark-broken] [--repair]`.
+/// Prints the report and exits non-zero if anything was found.
+async fn run_audit_command(database: &SqlDatabase, args: &[String]) -> i32 {
+    let options = match audit::AuditOptions::from_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "Usage: tag_manager audit [--regenerate-thumbnails] [--quarantine-orphans] [--mark-broken] [--repair]"
+            );
+            return 2;
+        }
+    };
+
+    match audit::run(database, options).await {
+        Ok(report) => {
+            print!("{report}");
+            if report.is_clean() { 0 } else { 1 }
+        }
+        Err(e) => {
+            error!(error = ?e, "Storage audit failed");
+            2
+        }
+    }
+}
 
 fn set_static_vars(config : &Config){
-    image_path::STORAGE_PATH.set(config.storage_path.clone()).unwrap();
-    image_path::IMPORT_PATH.set(config.import_path.clone()).unwrap();
+    image_path::STORAGE
+        .set(storage::Storage::new(&config.storage).expect("Could not set up storage"))
+        .unwrap();
     image_path::VIDEO_PATH.set(config.video_path.clone()).unwrap();
     image_path::DISCARD_PATH.set(config.discarded_path.clone()).unwrap();
+    image_path::QUARANTINE_PATH.set(config.quarantine_path.clone()).unwrap();
+    memory::MEMORY_BUDGET
+        .set(memory::MemoryBudget::new(config.memory_budget_mb))
+        .unwrap();
+    disk_guard::DISK_GUARD
+        .set(disk_guard::DiskGuard::new(
+            disk_volumes(config),
+            config.min_free_space_mb * 1024 * 1024,
+        ))
+        .unwrap();
+    rules::RULES
+        .set(rules::RulesFile::new(config.rules_file.clone()))
+        .unwrap();
     tag_fetcher::TAGSERVICE_URL.set(config.tagmanager_url.clone()).unwrap();
+    circuit_breaker::TAGGER
+        .set(circuit_breaker::CircuitBreaker::new(
+            config.tagger_failure_threshold,
+            Duration::from_secs(config.tagger_probe_interval_secs),
+        ))
+        .unwrap();
+    tag_fetcher::start_batcher(
+        config.tag_batch_size,
+        Duration::from_millis(config.tag_batch_wait_ms),
+    );
+}
+
+/// The local directories ingestion writes to. An object store isn't one of them.
+fn disk_volumes(config: &Config) -> Vec<disk_guard::Volume> {
+    let mut volumes = vec![
+        disk_guard::Volume {
+            name: "discard",
+            path: config.discarded_path.clone(),
+        },
+        disk_guard::Volume {
+            name: "video",
+            path: config.video_path.clone(),
+        },
+    ];
+    if let storage::StorageConfig::Local(path) = &config.storage {
+        volumes.push(disk_guard::Volume {
+            name: "storage",
+            path: path.clone(),
+        });
+    }
+    volumes
 }
 
 #[derive(Clone, Debug)]
 struct Config {
     connection_string: String,
-    storage_path: PathBuf,
-    import_path: PathBuf,
+    storage: storage::StorageConfig,
+    import_roots: Vec<import_root::ImportRoot>,
     discarded_path: PathBuf,
     video_path: PathBuf,
+    quarantine_path: PathBuf,
     tagmanager_url : String,
-    thumbnail_size: u32,
+    thumbnail_sizes: Vec<u32>,
+    thumbnail_formats: Vec<ThumbnailFormat>,
+    thumbnail_quality: u8,
+    thumbnail_background: [u8; 3],
+    tag_batch_size: usize,
+    tag_batch_wait_ms: u64,
+    tagger_failure_threshold: u32,
+    tagger_probe_interval_secs: u64,
+    metrics_address: Option<SocketAddr>,
+    log_format: String,
+    worker_id: String,
+    worker_timeout_secs: u64,
+    rules_file: PathBuf,
+    rules_dry_run: bool,
+    blocklist_distance: u32,
+    min_free_space_mb: u64,
+    schedules: Vec<scheduler::ScheduledTask>,
+    discard_retention_days: u64,
+    job_retry_limit: u32,
+    thumbnail_refresh_per_minute: u32,
+    memory_budget_mb: u32,
 }
 
 impl Config {
@@ -56,12 +334,11 @@ impl Config {
                 .get("DATABASE_URL")
                 .expect("database connection string is required")
                 .to_string(),
-            storage_path: PathBuf::from_str(
-                env.get("STORAGE_DIR").map_or("/Images/Storage", |v| v),
-            )
-            .expect("Invalid path"),
-            import_path: PathBuf::from_str(env.get("IMPORT_DIR").map_or("/Images/Import", |v| v))
-                .expect("Invalid import path"),
+            storage: storage::StorageConfig::from_env().expect("Invalid storage configuration"),
+            import_roots: import_roots(
+                env.get("CONFIG_FILE").map_or("/Images/tag_manager.toml", |v| v),
+                env.get("IMPORT_DIR").map_or("/Images/Import", |v| v),
+            ),
             discarded_path: PathBuf::from_str(
                 env.get("DISCARDED_DIR").map_or("/Images/Discard", |v| v),
             )
@@ -71,8 +348,141 @@ impl Config {
                     .map_or("/Images/Videos", |v| v),
             )
             .expect("Invalid other file type dir"),
+            quarantine_path: PathBuf::from_str(
+                env.get("QUARANTINE_DIR").map_or("/Images/Quarantine", |v| v),
+            )
+            .expect("Invalid quarantine dir"),
             tagmanager_url: std::env::var("TAGSERVICE_URL").unwrap_or("http://127.0.0.1:8000".to_string()),
-            thumbnail_size: std::env::var("THUMBNAIL_SIZE").map(|x| x.parse().expect("THUMBNAIL_SIZE not valid integer")).unwrap_or(600),
+            thumbnail_sizes: thumbnail_sizes(),
+            thumbnail_formats: thumbnail_formats(
+                &std::env::var("THUMBNAIL_FORMATS").unwrap_or("webp,jpeg".to_string()),
+            ),
+            thumbnail_quality: std::env::var("THUMBNAIL_QUALITY").map(|x| x.parse().expect("THUMBNAIL_QUALITY not valid integer")).unwrap_or(60),
+            thumbnail_background: hex_color(
+                &std::env::var("THUMBNAIL_BACKGROUND").unwrap_or("#ffffff".to_string()),
+            ),
+            tag_batch_size: std::env::var("TAG_BATCH_SIZE").map(|x| x.parse().expect("TAG_BATCH_SIZE not valid integer")).unwrap_or(8),
+            tag_batch_wait_ms: std::env::var("TAG_BATCH_WAIT_MS").map(|x| x.parse().expect("TAG_BATCH_WAIT_MS not valid integer")).unwrap_or(200),
+            tagger_failure_threshold: std::env::var("TAGGER_FAILURE_THRESHOLD").map(|x| x.parse().expect("TAGGER_FAILURE_THRESHOLD not valid integer")).unwrap_or(5),
+            tagger_probe_interval_secs: std::env::var("TAGGER_PROBE_INTERVAL_SECS").map(|x| x.parse().expect("TAGGER_PROBE_INTERVAL_SECS not valid integer")).unwrap_or(15),
+            log_format: std::env::var("LOG_FORMAT").unwrap_or("text".to_string()),
+            metrics_address: std::env::var("METRICS_ADDRESS")
+                .ok()
+                .filter(|x| !x.is_empty())
+                .map(|x| x.parse().expect("METRICS_ADDRESS not a valid socket address")),
+            rules_file: PathBuf::from_str(env.get("RULES_FILE").map_or("/Images/rules.toml", |v| v))
+                .expect("Invalid rules file path"),
+            blocklist_distance: std::env::var("BLOCKLIST_DISTANCE").map(|x| x.parse().expect("BLOCKLIST_DISTANCE not valid integer")).unwrap_or(4),
+            min_free_space_mb: std::env::var("MIN_FREE_SPACE_MB").map(|x| x.parse().expect("MIN_FREE_SPACE_MB not valid integer")).unwrap_or(1024),
+            rules_dry_run: std::env::var("RULES_DRY_RUN").map(|x| x.parse().expect("RULES_DRY_RUN not a valid bool")).unwrap_or(false),
+            worker_id: std::env::var("WORKER_ID").unwrap_or_else(|_| {
+                let host = std::env::var("HOSTNAME").unwrap_or("tag_manager".to_string());
+                format!("{host}-{}", std::process::id())
+            }),
+            worker_timeout_secs: std::env::var("WORKER_TIMEOUT_SECS").map(|x| x.parse().expect("WORKER_TIMEOUT_SECS not valid integer")).unwrap_or(60),
+            schedules: schedules(),
+            discard_retention_days: std::env::var("DISCARD_RETENTION_DAYS").map(|x| x.parse().expect("DISCARD_RETENTION_DAYS not valid integer")).unwrap_or(30),
+            memory_budget_mb: std::env::var("MEMORY_BUDGET_MB").map(|x| x.parse().expect("MEMORY_BUDGET_MB not valid integer")).unwrap_or(1024),
+            thumbnail_refresh_per_minute: std::env::var("THUMBNAIL_REFRESH_PER_MINUTE").map(|x| x.parse().expect("THUMBNAIL_REFRESH_PER_MINUTE not valid integer")).unwrap_or(30),
+            job_retry_limit: std::env::var("JOB_RETRY_LIMIT").map(|x| x.parse().expect("JOB_RETRY_LIMIT not valid integer")).unwrap_or(3),
         }
     }
 }
+
+/// The `[[import]]` roots of the config file, or `import_dir` without any
+/// defaults if it has none.
+fn import_roots(config_file: &str, import_dir: &str) -> Vec<import_root::ImportRoot> {
+    let roots = import_root::load(Path::new(config_file)).expect("Invalid CONFIG_FILE");
+    if roots.is_empty() {
+        vec![import_root::ImportRoot {
+            path: PathBuf::from_str(import_dir).expect("Invalid import path"),
+            ..Default::default()
+        }]
+    } else {
+        roots
+    }
+}
+
+/// `THUMBNAIL_SIZES`, or for deployments from before there were several sizes,
+/// the single `THUMBNAIL_SIZE` they rendered.
+fn thumbnail_sizes() -> Vec<u32> {
+    let sizes = match (std::env::var("THUMBNAIL_SIZES"), std::env::var("THUMBNAIL_SIZE")) {
+        (Ok(sizes), _) => sizes,
+        (Err(_), Ok(size)) => size,
+        (Err(_), Err(_)) => "200,600,1200".to_string(),
+    };
+    sizes
+        .split(',')
+        .map(|x| x.trim().parse().expect("THUMBNAIL_SIZES not a list of valid integers"))
+        .collect()
+}
+
+/// Every maintenance task with its `SCHEDULE_*` expression or its default,
+/// leaving out those set to `off`.
+fn schedules() -> Vec<scheduler::ScheduledTask> {
+    scheduler::Task::ALL
+        .into_iter()
+        .filter_map(|task| {
+            let expression =
+                std::env::var(task.env_var()).unwrap_or(task.default_schedule().to_string());
+            scheduler::parse_schedule(task, &expression).expect("Invalid task schedule")
+        })
+        .collect()
+}
+
+/// Logs to stdout as text or, with `LOG_FORMAT=json`, one JSON object per line.
+/// Filtered by `RUST_LOG` like tag_api, defaulting to `info`.
+fn init_logging(format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        "json" => subscriber.json().init(),
+        "text" => subscriber.init(),
+        other => panic!("LOG_FORMAT must be text or json, not {other}"),
+    }
+}
+
+/// Parses the configured thumbnail formats. JPEG is always included as the
+/// fallback for clients that accept neither WebP nor AVIF.
+fn thumbnail_formats(value: &str) -> Vec<ThumbnailFormat> {
+    let mut seen = HashSet::new();
+    value
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| {
+            x.parse()
+                .expect("THUMBNAIL_FORMATS contains an unknown format")
+        })
+        .chain([ThumbnailFormat::Jpeg])
+        .filter(|x| seen.insert(*x))
+        .collect()
+}
+
+fn hex_color(value: &str) -> [u8; 3] {
+    let value = value.trim_start_matches('#');
+    let channel = |index: usize| {
+        value
+            .get(index * 2..index * 2 + 2)
+            .and_then(|x| u8::from_str_radix(x, 16).ok())
+            .expect("THUMBNAIL_BACKGROUND not a valid hex color")
+    };
+    [channel(0), channel(1), channel(2)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_formats_keep_order_and_always_include_jpeg() {
+        assert_eq!(
+            thumbnail_formats("avif, webp,avif,,jpg"),
+            [ThumbnailFormat::Avif, ThumbnailFormat::WebP, ThumbnailFormat::Jpeg]
+        );
+        assert_eq!(
+            thumbnail_formats("webp"),
+            [ThumbnailFormat::WebP, ThumbnailFormat::Jpeg]
+        );
+        assert_eq!(thumbnail_formats(""), [ThumbnailFormat::Jpeg]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::OnceLock;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::debug;
+
+pub static MEMORY_BUDGET: OnceLock<MemoryBudget> = OnceLock::new();
+
+pub fn memory_budget() -> &'static MemoryBudget {
+    MEMORY_BUDGET.get().unwrap()
+}
+
+/// Bounds the memory held by decoded images across every import, tagging and
+/// thumbnail task, in MiB. Decodes wait for their share rather than run the
+/// process out of memory; waiters are served in order, so a large image isn't
+/// starved by a stream of small ones.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    permits: Semaphore,
+    total_mib: u32,
+}
+
+/// A share of the budget, returned when dropped.
+pub struct MemoryPermit {
+    _permit: SemaphorePermit<'static>,
+}
+
+impl MemoryBudget {
+    pub fn new(total_mib: u32) -> Self {
+        let total_mib = total_mib.max(1);
+        Self {
+            permits: Semaphore::new(total_mib as usize),
+            total_mib,
+        }
+    }
+
+    /// Waits until `bytes` fit in the budget. A request larger than the whole
+    /// budget waits for all of it, so such an image is decoded alone instead of
+    /// never.
+    pub async fn reserve(&'static self, bytes: u64) -> MemoryPermit {
+        let mib = bytes.div_ceil(1 << 20).clamp(1, u64::from(self.total_mib)) as u32;
+        if self.permits.available_permits() < mib as usize {
+            debug!(mib, "Waiting for memory budget");
+        }
+        let permit = self
+            .permits
+            .acquire_many(mib)
+            .await
+            .expect("Memory budget semaphore is never closed");
+        MemoryPermit { _permit: permit }
+    }
+}
@@ -0,0 +1,206 @@
+use image::{DynamicImage, imageops::FilterType};
+use storage::color::srgb_to_lab;
+
+/// Longest side of the copy the palette is extracted from.
+const ANALYSIS_SIZE: u32 = 64;
+/// Upper bound of colors kept per image; clusters nothing was assigned to are dropped.
+const PALETTE_SIZE: usize = 5;
+const ITERATIONS: usize = 12;
+/// Pixels with less chroma than this (in CIELAB units) count as gray.
+const GRAY_CHROMA: f32 = 6.0;
+/// Share of opaque pixels that has to be gray for the image to count as monochrome,
+/// so a signature or a bit of JPEG noise doesn't disqualify a sketch.
+const GRAY_SHARE: f32 = 0.98;
+
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    pub lab: [f32; 3],
+    /// Share of the image's opaque pixels closest to this color.
+    pub weight: f32,
+}
+
+/// Dominant colors of an image, most common first.
+pub struct Palette {
+    pub colors: Vec<PaletteColor>,
+    pub grayscale: bool,
+    pub transparent: bool,
+}
+
+/// Clusters the pixels of a small copy of the image with k-means in CIELAB, so
+/// colors that look alike end up together. Mostly transparent pixels are ignored.
+pub fn extract(image: &DynamicImage) -> Palette {
+    let small = image
+        .resize(ANALYSIS_SIZE, ANALYSIS_SIZE, FilterType::Triangle)
+        .to_rgba8();
+    let transparent = image.color().has_alpha() && small.pixels().any(|x| x[3] < 255);
+
+    let pixels: Vec<([u8; 3], [f32; 3])> = small
+        .pixels()
+        .filter(|x| x[3] >= 128)
+        .map(|x| ([x[0], x[1], x[2]], srgb_to_lab([x[0], x[1], x[2]])))
+        .collect();
+    if pixels.is_empty() {
+        return Palette {
+            colors: Vec::new(),
+            grayscale: false,
+            transparent,
+        };
+    }
+
+    let gray = pixels
+        .iter()
+        .filter(|(_, lab)| lab[1].hypot(lab[2]) < GRAY_CHROMA)
+        .count();
+    let grayscale = gray as f32 >= pixels.len() as f32 * GRAY_SHARE;
+
+    // Seeding from lightness quantiles keeps the result deterministic, so
+    // re-rendering an image doesn't shuffle its palette.
+    let mut by_lightness: Vec<[f32; 3]> = pixels.iter().map(|(_, lab)| *lab).collect();
+    by_lightness.sort_unstable_by(|a, b| a[0].total_cmp(&b[0]));
+    let clusters = PALETTE_SIZE.min(pixels.len());
+    let mut centroids: Vec<[f32; 3]> = (0..clusters)
+        .map(|i| by_lightness[(2 * i + 1) * by_lightness.len() / (2 * clusters)])
+        .collect();
+
+    let mut assignment = vec![0usize; pixels.len()];
+    for _ in 0..ITERATIONS {
+        let mut changed = false;
+        for (index, (_, lab)) in pixels.iter().enumerate() {
+            let nearest = nearest(&centroids, lab);
+            if assignment[index] != nearest {
+                assignment[index] = nearest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![([0.0f32; 3], 0usize); centroids.len()];
+        for (index, (_, lab)) in pixels.iter().enumerate() {
+            let (sum, count) = &mut sums[assignment[index]];
+            sum.iter_mut().zip(lab).for_each(|(s, v)| *s += v);
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|x| x / count as f32);
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    // Report the average sRGB of each cluster rather than converting the centroid
+    // back, which avoids out of gamut results.
+    let mut totals = vec![([0u64; 3], 0usize); centroids.len()];
+    for (index, (rgb, _)) in pixels.iter().enumerate() {
+        let (sum, count) = &mut totals[assignment[index]];
+        sum.iter_mut().zip(rgb).for_each(|(s, v)| *s += *v as u64);
+        *count += 1;
+    }
+
+    let mut colors: Vec<PaletteColor> = centroids
+        .into_iter()
+        .zip(totals)
+        .filter(|(_, (_, count))| *count > 0)
+        .map(|(lab, (sum, count))| PaletteColor {
+            rgb: sum.map(|x| (x / count as u64) as u8),
+            lab,
+            weight: count as f32 / pixels.len() as f32,
+        })
+        .collect();
+    colors.sort_unstable_by(|a, b| b.weight.total_cmp(&a.weight));
+
+    Palette {
+        colors,
+        grayscale,
+        transparent,
+    }
+}
+
+fn nearest(centroids: &[[f32; 3]], lab: &[f32; 3]) -> usize {
+    centroids
+        .iter()
+        .map(|centroid| {
+            centroid
+                .iter()
+                .zip(lab)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+        })
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn split(width: u32, left: Rgb<u8>, right: Rgb<u8>, at: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, width, |x, _| {
+            if x < at { left } else { right }
+        }))
+    }
+
+    #[test]
+    fn single_color_image() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([200, 30, 40])));
+        let palette = extract(&image);
+        assert_eq!(palette.colors.len(), 1);
+        assert_eq!(palette.colors[0].rgb, [200, 30, 40]);
+        assert_eq!(palette.colors[0].weight, 1.0);
+        assert!(!palette.grayscale);
+        assert!(!palette.transparent);
+    }
+
+    #[test]
+    fn colors_are_sorted_by_weight() {
+        let image = split(64, Rgb([220, 20, 20]), Rgb([20, 20, 220]), 48);
+        let palette = extract(&image);
+        let [first, second] = &palette.colors[..2] else {
+            panic!("expected two colors");
+        };
+        assert!(first.weight > second.weight);
+        assert!(first.rgb[0] > 180 && first.rgb[2] < 60, "{:?}", first.rgb);
+        assert!(second.rgb[2] > 180 && second.rgb[0] < 60, "{:?}", second.rgb);
+        let total: f32 = palette.colors.iter().map(|x| x.weight).sum();
+        assert!((total - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn gray_image_is_grayscale() {
+        let image = split(64, Rgb([30, 30, 30]), Rgb([220, 220, 220]), 32);
+        assert!(extract(&image).grayscale);
+    }
+
+    #[test]
+    fn transparent_pixels_are_ignored() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 { Rgba([0, 200, 0, 255]) } else { Rgba([255, 0, 0, 0]) }
+        }));
+        let palette = extract(&image);
+        assert!(palette.transparent);
+        assert!(palette.colors.iter().all(|x| x.rgb[0] < 60), "red leaked in");
+    }
+
+    #[test]
+    fn fully_transparent_image_has_no_colors() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 0])));
+        let palette = extract(&image);
+        assert!(palette.colors.is_empty());
+        assert!(palette.transparent);
+    }
+
+    #[test]
+    fn extraction_is_deterministic() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let first: Vec<_> = extract(&image).colors.iter().map(|x| x.rgb).collect();
+        let second: Vec<_> = extract(&image).colors.iter().map(|x| x.rgb).collect();
+        assert_eq!(first, second);
+    }
+}
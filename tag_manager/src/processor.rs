@@ -1,62 +1,259 @@
 use anyhow::{Result, anyhow};
 use futures::{StreamExt, stream};
 use image::DynamicImage;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use std::path::PathBuf;
+use metrics::{counter, gauge};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 
 use crate::{
+    circuit_breaker,
+    crop::{self, CropRect},
     database::Database,
-    image_path::{to_discarded, to_storage, to_storage_thumbnail, to_video},
-    tag_fetcher::{self, ImageFetcherError},
+    decode::{Decoded, Source, decode_image, decode_stored, decode_stored_scaled},
+    disk_guard::disk_guard,
+    image_path::{original_key, storage, thumbnail_key, to_discarded, to_video},
+    import_root::{self, ImportMode, ImportRoot},
+    ingest::{IngestJob, Stage},
+    palette,
+    rules::{self, ImageFacts},
+    tag_fetcher::{self, ImageFetcherError, Tags},
+    telemetry::{self, timed, timed_blocking},
+    thumbnail::{self, ThumbnailFormat, Variant},
 };
 
-pub async fn process_images(database: &(impl Database + Clone)) -> Result<()> {
-    let files = get_image_paths(&database.config().import_path)?;
+/// Files imported at the same time by one worker. Jobs are claimed in batches of
+/// this size, so a worker never holds more than it is working on.
+pub const IMPORT_CONCURRENCY: usize = 12;
+/// Images tagged at the same time. Tagging runs alongside imports, so the two
+/// together have to leave room in the connection pool.
+pub const TAG_CONCURRENCY: usize = 8;
+const VIDEO_EXTENSIONS: [&str; 5] = ["webm", "mov", "mp4", "flv", "avi"];
 
-    stream::iter(files)
-        .map(|path| {
-            let db = database.clone();
-            async move {
-                let extension = path
-                    .extension()
-                    .map(|x| x.to_str().unwrap())
-                    .unwrap_or("png");
-                if ["webm", "mov", "mp4", "flv", "avi"].contains(&extension) {
-                    process_video(&path, extension).await
-                } else {
-                    match process_image(&db, &path).await {
-                        Ok(_) => Ok(()),
-                        Err(e) => {
-                            if let Some(api_error) = e.downcast_ref::<ImageFetcherError>() {
-                                println!("API failure: {api_error}");
-                            } else {
-                                println!(
-                                    "Something went wrong processing file: {path:?} with error: {e:?}",
-                                );
-                                let new_path = to_discarded();
-                                if let Err(e) = tokio::fs::rename(path, new_path).await {
-                                    println!("Could not move errored file: {e}");
-                                }
-                            }
-                            Err::<(), _>(e)
-                        }
-                    }
+/// Queues the files in the import directory as ingest jobs, then imports jobs
+/// claimed from the queue until it is empty, then backfills. Several workers can
+/// share one database and import directory. Once `shutdown` is cancelled no new
+/// jobs are started, while those in flight are finished. Nothing is started while
+/// the disk guard has ingestion paused either; the next run checks again.
+pub async fn process_images(
+    database: &(impl Database + Clone),
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    if !disk_guard().check() {
+        return Ok(());
+    }
+    let roots = &database.config().import_roots;
+    for root in roots {
+        let keep_file = root.mode == ImportMode::Copy;
+        for path in get_image_paths(root, roots)? {
+            let extension = path
+                .extension()
+                .map(|x| x.to_str().unwrap())
+                .unwrap_or("png");
+            if VIDEO_EXTENSIONS.contains(&extension) {
+                // Videos aren't tracked, so one in a copy root would be copied
+                // again on every run.
+                if keep_file {
+                    continue;
                 }
+                counter!(telemetry::FILES_DISCOVERED, "kind" => "video").increment(1);
+                info!(path = %path.display(), "Moving video");
+                if let Err(e) = process_video(&path, extension).await {
+                    error!(path = %path.display(), error = %e, "Could not move video");
+                }
+            } else if database.discover_job(&path, keep_file).await? {
+                counter!(telemetry::FILES_DISCOVERED, "kind" => "image").increment(1);
             }
-        })
-        .buffer_unordered(12)
-        .collect::<Vec<_>>()
-        .await;
+        }
+    }
+    gauge!(telemetry::IMPORT_BACKLOG).set(database.count_pending_jobs().await? as f64);
+
+    while !shutdown.is_cancelled() && disk_guard().check() {
+        let jobs = database.claim_jobs(IMPORT_CONCURRENCY).await?;
+        if jobs.is_empty() {
+            break;
+        }
+        stream::iter(jobs)
+            // Checked before each job, as a batch can fill the disk by itself.
+            // Jobs left unstarted stay claimed and are picked up again next run.
+            .take_while(|_| std::future::ready(!shutdown.is_cancelled() && disk_guard().check()))
+            .map(|job| {
+                let db = database.clone();
+                let span = info_span!(
+                    "import",
+                    path = %job.path.display(),
+                    job_id = job.id,
+                    hash = field::Empty,
+                    image_id = field::Empty
+                );
+                async move {
+                    let result = import_file(&db, job).await;
+                    gauge!(telemetry::IMPORT_BACKLOG).decrement(1.0);
+                    result
+                }
+                .instrument(span)
+            })
+            .buffer_unordered(IMPORT_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+    }
 
+    if shutdown.is_cancelled() || !disk_guard().check() {
+        return Ok(());
+    }
+    // Backfills only need one worker; the others skip them until their next run.
+    let Some(_lock) = database.try_exclusive("backfill").await? else {
+        return Ok(());
+    };
+    let pruned = database.prune_jobs().await?;
+    if pruned > 0 {
+        info!(pruned, "Dropped old ingest jobs");
+    }
     thumbnail_images(database).await?;
+    blurhash_images(database).await?;
+    palette_images(database).await?;
     Ok(())
 }
 
-fn get_image_paths(import_path: &PathBuf) -> Result<Vec<PathBuf>> {
-    Ok(std::fs::read_dir(import_path)?
-        .filter_map(Result::ok)
-        .map(|x| x.path())
-        .collect())
+async fn import_file(database: &impl Database, mut job: IngestJob) -> Result<()> {
+    if job.stage != Stage::Discovered {
+        info!(stage = %job.stage, "Resuming interrupted import");
+    }
+    if let Some(id) = job.image_id {
+        Span::current().record("image_id", id);
+    }
+    // Uploads have no root and bring their own defaults. A job whose root was
+    // removed from the config since is imported without defaults.
+    let root = import_root::find(&database.config().import_roots, &job.path)
+        .cloned()
+        .unwrap_or_default();
+
+    match process_image(database, &mut job, &root).await {
+        Ok(_) => {
+            counter!(telemetry::FILES_PROCESSED).increment(1);
+            info!("Stored image");
+            Ok(())
+        }
+        Err(e) => {
+            let reason = discard_reason(&e);
+            warn!(reason, stage = %job.stage, error = ?e, "Discarding file");
+            counter!(telemetry::FILES_DISCARDED, "reason" => reason).increment(1);
+            // Once thumbnailed the image is complete, only removing the file failed.
+            if job.stage < Stage::Thumbnailed
+                && let Some(id) = job.image_id
+                && let Err(rollback_error) = rollback(database, id).await
+            {
+                error!(error = ?rollback_error, "Could not roll back partially stored image");
+            }
+            // Failures that aren't down to the file, such as storage being
+            // unreachable, are left for the retry task.
+            let retryable = !matches!(reason, "duplicate" | "blocked" | "decode");
+            let discarded = (root.mode == ImportMode::Move).then(to_discarded);
+            if let Err(e) = database
+                .fail_job(job.id, &format!("{e:#}"), retryable, discarded.as_deref())
+                .await
+            {
+                error!(error = ?e, "Could not mark ingest job failed");
+            }
+            if let Some(discarded) = discarded
+                && let Err(e) = tokio::fs::rename(&job.path, discarded).await
+            {
+                error!(error = %e, "Could not move errored file");
+            }
+            Err(e)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DuplicateImage;
+
+impl fmt::Display for DuplicateImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "File duplicate.")
+    }
+}
+
+impl std::error::Error for DuplicateImage {}
+
+/// Close to a hash on the blocklist, the reason it was blocked.
+#[derive(Debug)]
+struct BlockedImage(String);
+
+impl fmt::Display for BlockedImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Blocked: {}", self.0)
+    }
+}
+
+impl std::error::Error for BlockedImage {}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// The `reason` label of a discarded import.
+fn discard_reason(error: &anyhow::Error) -> &'static str {
+    if error.is::<DuplicateImage>() {
+        "duplicate"
+    } else if error.is::<BlockedImage>() {
+        "blocked"
+    } else if error.is::<image::ImageError>() {
+        "decode"
+    } else if error.is::<sqlx::Error>() {
+        "database"
+    } else if error.is::<std::io::Error>() {
+        "io"
+    } else {
+        "other"
+    }
+}
+
+/// Files in `root`, and with `recursive` in its subdirectories. Directories
+/// that are roots of their own are left to be scanned as such.
+fn get_image_paths(root: &ImportRoot, roots: &[ImportRoot]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut directories = vec![root.path.clone()];
+    while let Some(directory) = directories.pop() {
+        for entry in std::fs::read_dir(&directory)?.filter_map(Result::ok) {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = entry.path();
+            if file_type.is_file() {
+                files.push(path);
+            } else if file_type.is_dir()
+                && root.recursive
+                && !roots.iter().any(|x| x.path == path)
+            {
+                directories.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Removes the directories `file` was in that are empty now, up to but not
+/// including `root`. Stops at the first one that still has files in it.
+fn remove_empty_parents(file: &Path, root: &Path) {
+    // Uploads and files of a removed root have no root to stop at.
+    if root.as_os_str().is_empty() {
+        return;
+    }
+    for directory in file.ancestors().skip(1) {
+        if directory == root || !directory.starts_with(root) {
+            break;
+        }
+        if std::fs::remove_dir(directory).is_err() {
+            break;
+        }
+        debug!(directory = %directory.display(), "Removed empty import directory");
+    }
 }
 
 async fn process_video(path: &PathBuf, extension: &str) -> Result<()> {
@@ -67,93 +264,369 @@ async fn process_video(path: &PathBuf, extension: &str) -> Result<()> {
     Ok(())
 }
 
-async fn process_image(database: &impl Database, path: &PathBuf) -> Result<()> {
-    let path_n = path.clone();
-    let image = tokio::task::spawn_blocking(move || {
-        image::io::Reader::open(&path_n).map(|ok| {
-            ok.with_guessed_format().map(|mut okk| {
-                okk.no_limits();
-                okk.decode()
-            })
-        })
-    })
-    .await????;
-    let hash: [u8; 8] = imagehash::average_hash(&image)
-        .to_bytes()
-        .try_into()
-        .unwrap();
-    let exists = database.check_hash(&hash).await?;
-    if exists {
-        return Err(anyhow!("File duplicate."));
+/// Stores an image without waiting for the tag service. The row stays untagged,
+/// and so only visible to admins, until `tag_pending_images` picks it up.
+///
+/// Runs the stages `job` hasn't completed yet, recording each one, so an import
+/// interrupted by a crash picks up where it stopped instead of being treated as
+/// a duplicate of its own half-stored image.
+async fn process_image(
+    database: &impl Database,
+    job: &mut IngestJob,
+    root: &ImportRoot,
+) -> Result<()> {
+    // The decoded image is handed from stage to stage, and only decoded again
+    // when resuming. It holds its share of the memory budget until the last
+    // stage that reads it is done.
+    let mut image: Option<Decoded> = None;
+
+    if job.stage == Stage::Discovered {
+        let decoded = timed("decode", decode_image(job.path.clone())).await?;
+        let hash: [u8; 8] = timed_blocking("hash", || imagehash::average_hash(&decoded.image))
+            .to_bytes()
+            .try_into()
+            .unwrap();
+        Span::current().record("hash", field::display(hex(&hash)));
+        if let Some(reason) = database.get_blocked_reason(&hash).await? {
+            return Err(BlockedImage(reason).into());
+        }
+        let defaults = match &job.defaults {
+            Some(defaults) => defaults.clone(),
+            None => root.defaults_for(&job.path),
+        };
+        let Some(id) = database.save_image(job.id, &hash, &defaults).await? else {
+            return Err(DuplicateImage.into());
+        };
+        Span::current().record("image_id", id);
+        job.image_id = Some(id);
+        job.stage = Stage::Decoded;
+        image = Some(decoded);
     }
-    let tags = tag_fetcher::fetch_tags(&image).await?;
-    let id = database.save_image(&hash, &tags).await?;
 
-    let new_path = to_storage(id);
-    let image_copy = image.clone();
-    if let Err(e) = tokio::task::spawn_blocking(move || {
-        image.save_with_format(new_path, image::ImageFormat::Png)
-    })
-    .await?
-    {
-        println!("Error saving file!!! bad!!!!, remove {id}, from db, {e}",);
+    let id = job
+        .image_id
+        .ok_or_else(|| anyhow!("Ingest job {} has no image", job.id))?;
+
+    if job.stage == Stage::Decoded {
+        let decoded = match image.take() {
+            Some(image) => image,
+            None => timed("decode", decode_image(job.path.clone())).await?,
+        };
+        let checksum = timed("save", store_original(id, decoded.source.clone())).await?;
+        database.write_checksum(id, &checksum).await?;
+        database.advance_job(job.id, Stage::Stored).await?;
+        job.stage = Stage::Stored;
+        image = Some(decoded);
+    }
+
+    if job.stage == Stage::Stored {
+        let decoded = match image.take() {
+            Some(image) => image,
+            None => timed("decode", decode_stored(original_key(id))).await?,
+        };
+        let thumbnails = thumbnail_image_from_file(database, id, decoded.image.clone());
+        timed("thumbnail", thumbnails).await?;
+        database.advance_job(job.id, Stage::Thumbnailed).await?;
+        job.stage = Stage::Thumbnailed;
+    }
+
+    if job.stage == Stage::Thumbnailed {
+        if root.mode == ImportMode::Move {
+            match tokio::fs::remove_file(&job.path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            remove_empty_parents(&job.path, &root.path);
+        }
+        database.advance_job(job.id, Stage::Done).await?;
+        job.stage = Stage::Done;
+    }
+
+    Ok(())
+}
+
+/// Deletes an image's files and rows. Undoes a failed import this way, so the file
+/// can be imported again from scratch rather than leaving a row without a file or
+/// thumbnails.
+async fn rollback(database: &impl Database, id: u32) -> Result<()> {
+    let config = database.config();
+    storage().delete(&original_key(id)).await?;
+    // The renditions recorded for the image, which may have been rendered with
+    // earlier settings, and those the current settings write before recording them.
+    let mut renditions = database.get_renditions(id).await?;
+    for variant in [Variant::Fit, Variant::Square] {
+        for size in &config.thumbnail_sizes {
+            for format in &config.thumbnail_formats {
+                if !renditions.contains(&(variant, *size, *format)) {
+                    renditions.push((variant, *size, *format));
+                }
+            }
+        }
+    }
+    for (variant, size, format) in renditions {
+        storage()
+            .delete(&thumbnail_key(id, variant, size, format))
+            .await?;
     }
+    database.delete_image(id).await
+}
 
-    thumbnail_image_from_file(database, id, image_copy).await?;
+/// Removes an image an ingest rule discarded. The original is kept in the discard
+/// directory, like any other discarded import.
+async fn discard_stored(database: &impl Database, id: u32) -> Result<()> {
+    if let Some(bytes) = storage().get(&original_key(id)).await? {
+        tokio::fs::write(to_discarded(), bytes).await?;
+    }
+    rollback(database, id).await
+}
+
+/// Stores the original as PNG and returns the SHA-256 of the stored bytes, which
+/// the storage audit later verifies the file against.
+async fn store_original(id: u32, source: Source) -> Result<[u8; 32]> {
+    let bytes = tokio::task::spawn_blocking(move || source.to_png()).await??;
+    storage().put(&original_key(id), &bytes).await?;
+    Ok(Sha256::digest(&bytes).into())
+}
+
+/// Tags every stored image that is still waiting on the tag service. Images that
+/// fail because the service is unreachable stay untagged and are retried later.
+pub async fn tag_pending_images(
+    database: &(impl Database + Clone),
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    if !circuit_breaker::tagger().is_closed() {
+        return Ok(());
+    }
+    // Workers would all pick the same untagged images, so one tags at a time.
+    let Some(_lock) = database.try_exclusive("tagging").await? else {
+        return Ok(());
+    };
+
+    if let Some(model) = tag_fetcher::model_version().await {
+        let purged = database.purge_tag_cache(&model).await?;
+        if purged > 0 {
+            info!(purged, model = %model, "Dropped cached tag results from previous models");
+        }
+    }
+
+    let untagged = database.get_untagged_images().await?;
+
+    stream::iter(untagged)
+        .take_while(|_| std::future::ready(!shutdown.is_cancelled()))
+        .map(|image_id| {
+            let db = database.clone();
+            let span = info_span!("tag", image_id);
+            async move {
+                if !circuit_breaker::tagger().is_closed() {
+                    return Ok(());
+                }
+                match tag_stored_image(&db, image_id).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        if let Some(api_error) = e.downcast_ref::<ImageFetcherError>() {
+                            warn!(image_id, error = %api_error, "Tag service failed to tag image");
+                        } else {
+                            error!(image_id, error = ?e, "Could not tag image");
+                        }
+                        Err(e)
+                    }
+                }
+            }
+            .instrument(span)
+        })
+        .buffer_unordered(TAG_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
 
-    tokio::fs::remove_file(path).await?;
     Ok(())
 }
 
+/// Tags a stored image, then applies its import defaults and the ingest rules.
+async fn tag_stored_image(database: &impl Database, image_id: u32) -> Result<()> {
+    let decoded = decode_stored(original_key(image_id)).await?;
+    let (width, height) = (decoded.image.width(), decoded.image.height());
+    let mut tags = timed("tag", tag_image(database, decoded)).await?;
+    let defaults = database.get_import_defaults(image_id).await?;
+    defaults.apply(&mut tags);
+
+    let facts = ImageFacts {
+        width,
+        height,
+        source: defaults.source.as_deref(),
+        artist: defaults.artist.as_deref(),
+    };
+    let rules = rules::rules();
+    if database.config().rules_dry_run {
+        rules::evaluate(&rules, &mut tags.clone(), &facts, true);
+        return database.write_tags(image_id, &tags, false).await;
+    }
+
+    let outcome = rules::evaluate(&rules, &mut tags, &facts, false);
+    if let Some(reason) = outcome.discard {
+        warn!(reason, block = outcome.block, "Discarding image by rule");
+        counter!(telemetry::FILES_DISCARDED, "reason" => "rule").increment(1);
+        if outcome.block {
+            database.block_image(image_id, &reason).await?;
+        }
+        return discard_stored(database, image_id).await;
+    }
+    database.write_tags(image_id, &tags, outcome.private).await
+}
+
+/// Tags an image, reusing an earlier result for the same pixels and tagger model
+/// so requeued or retried files don't hit the tag service again. The tag service
+/// is sent a reduced copy, and the full size pixels are released before waiting
+/// on it.
+async fn tag_image(database: &impl Database, decoded: Decoded) -> Result<Tags> {
+    let Some(model) = tag_fetcher::model_version().await else {
+        let image = tag_fetcher::downscale(decoded).await?;
+        return Ok(tag_fetcher::fetch_tags(&image).await?);
+    };
+
+    let content_hash = content_hash(&decoded.image);
+    if let Some(tags) = database.get_cached_tags(&content_hash, &model).await? {
+        return Ok(tags);
+    }
+
+    let image = tag_fetcher::downscale(decoded).await?;
+    let tags = tag_fetcher::fetch_tags(&image).await?;
+    database.cache_tags(&content_hash, &model, &tags).await?;
+    Ok(tags)
+}
+
+/// SHA-256 over the decoded pixels, so a renamed or re-encoded copy of the same
+/// image still hits the cache.
+fn content_hash(image: &DynamicImage) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(image.width().to_le_bytes());
+    hasher.update(image.height().to_le_bytes());
+    hasher.update(format!("{:?}", image.color()).as_bytes());
+    hasher.update(image.as_bytes());
+    hasher.finalize().into()
+}
+
 async fn thumbnail_images(database: &impl Database) -> Result<()> {
     let non_processed_images = database.get_non_thumbnailed_images().await?;
 
     stream::iter(non_processed_images)
         .map(|image| {
-            let db = database.clone();
             async move {
-                match thumbnail_image(db, image).await{
+                match thumbnail_image(database, image).await{
                     Ok(_) => Ok(()),
                     Err(e) => {
-                        println!("Something went wrong processing file: {image}, with error: {e}");
+                        error!(image_id = image, error = %e, "Could not render thumbnails");
                         Err(e)
                     }
                 }
             }
         })
-        .buffer_unordered(12)
+        .buffer_unordered(IMPORT_CONCURRENCY)
         .collect::<Vec<_>>()
         .await;
 
     Ok(())
 }
-async fn thumbnail_image(database: &impl Database, image_id: u32) -> Result<()> {
-    let path = to_storage(image_id);
-    let image = tokio::task::spawn_blocking(move || {
-        image::io::Reader::open(&path).map(|ok| {
-            ok.with_guessed_format().map(|mut okk| {
-                okk.no_limits();
-                okk.decode()
-            })
-        })
-    })
-    .await????;
+pub async fn thumbnail_image(database: &impl Database, image_id: u32) -> Result<()> {
+    let decoded = decode_stored(original_key(image_id)).await?;
 
-    thumbnail_image_from_file(database, image_id, image).await
+    thumbnail_image_from_file(database, image_id, decoded.image.clone()).await
 }
 
 async fn thumbnail_image_from_file(
     database: &impl Database,
     image_id: u32,
-    image: DynamicImage,
+    image: Arc<DynamicImage>,
 ) -> Result<()> {
-    let thumbnail = image.resize(
-        database.config().thumbnail_size,
-        database.config().thumbnail_size,
-        image::imageops::FilterType::Lanczos3,
-    );
-    let output_path = to_storage_thumbnail(image_id);
-    let mut output = std::fs::File::create(output_path)?;
-    thumbnail.write_to(&mut output, image::ImageOutputFormat::Jpeg(60))?;
-    database.write_thumbnail(image_id).await
+    let config = database.config();
+    let sizes = config.thumbnail_sizes.clone();
+    let formats = config.thumbnail_formats.clone();
+    let quality = config.thumbnail_quality;
+    let background = config.thumbnail_background;
+    let crop_override = database.get_crop_override(image_id).await?;
+    let (crop, blurhash, palette, renditions) = tokio::task::spawn_blocking(move || {
+        let crop = match crop_override {
+            Some((x, y, size)) => CropRect::clamped(x, y, size, image.width(), image.height()),
+            None => crop::square_crop(&image),
+        };
+        let blurhash = thumbnail::blurhash(&image)?;
+        let palette = palette::extract(&image);
+        let mut renditions =
+            thumbnail::render(&image, Variant::Fit, &sizes, &formats, quality, background)?;
+        renditions.extend(thumbnail::render(
+            &crop.apply(&image),
+            Variant::Square,
+            &sizes,
+            &formats,
+            quality,
+            background,
+        )?);
+        anyhow::Ok((crop, blurhash, palette, renditions))
+    })
+    .await??;
+
+    let previous = database.get_renditions(image_id).await?;
+    for rendition in &renditions {
+        let key = thumbnail_key(image_id, rendition.variant, rendition.size, rendition.format);
+        storage().put(&key, &rendition.bytes).await?;
+    }
+    database.write_thumbnails(image_id, &renditions, crop).await?;
+    // Re-rendering with other settings can leave sizes or formats no longer
+    // configured, which nothing refers to anymore.
+    for (variant, size, format) in previous {
+        if !renditions
+            .iter()
+            .any(|x| x.variant == variant && x.size == size && x.format == format)
+            && let Err(e) = storage()
+                .delete(&thumbnail_key(image_id, variant, size, format))
+                .await
+        {
+            warn!(image_id, error = %e, "Could not delete old thumbnail");
+        }
+    }
+    database.write_blurhash(image_id, &blurhash).await?;
+    database.write_palette(image_id, &palette).await
+}
+
+/// Backfills placeholders for images thumbnailed before blurhashes existed, from
+/// their smallest JPEG rendition rather than the full size original, decoded at
+/// a reduced scale as the blurhash only looks at 32x32 pixels.
+async fn blurhash_images(database: &impl Database) -> Result<()> {
+    let Some(size) = database.config().thumbnail_sizes.iter().min().copied() else {
+        return Ok(());
+    };
+
+    for image_id in database.get_images_without_blurhash().await? {
+        let key = thumbnail_key(image_id, Variant::Fit, size, ThumbnailFormat::Jpeg);
+        let result = async {
+            let image = decode_stored_scaled(key, 32).await?;
+            let blurhash =
+                tokio::task::spawn_blocking(move || thumbnail::blurhash(&image)).await??;
+            database.write_blurhash(image_id, &blurhash).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(image_id, error = %e, "Could not compute blurhash");
+        }
+    }
+
+    Ok(())
+}
+
+/// Backfills palettes for images stored before they were extracted. Unlike the
+/// blurhash backfill this reads the original, as JPEG renditions have lost alpha.
+async fn palette_images(database: &impl Database) -> Result<()> {
+    for image_id in database.get_images_without_palette().await? {
+        let result = async {
+            let decoded = decode_stored(original_key(image_id)).await?;
+            let palette =
+                tokio::task::spawn_blocking(move || palette::extract(&decoded.image)).await?;
+            database.write_palette(image_id, &palette).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(image_id, error = %e, "Could not extract palette");
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,353 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::tag_fetcher::{Rating, Tags};
+
+pub static RULES: OnceLock<RulesFile> = OnceLock::new();
+
+/// The rules as of the last change to the rules file.
+pub fn rules() -> Arc<Vec<Rule>> {
+    RULES.get().unwrap().current()
+}
+
+/// One `[[rule]]` of the rules file. Every condition in `when` has to hold for
+/// `then` to be applied; an empty `when` matches every image.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub when: Condition,
+    pub then: Action,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Condition {
+    pub tags_any: Vec<String>,
+    pub tags_all: Vec<String>,
+    pub tags_none: Vec<String>,
+    pub characters_any: Vec<String>,
+    /// Any of these ratings.
+    pub rating: Vec<Rating>,
+    /// Any of these import sources.
+    pub source: Vec<String>,
+    pub artist: Vec<String>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Height divided by width, e.g. 4 for a long vertical strip.
+    pub min_aspect_ratio: Option<f32>,
+    pub max_aspect_ratio: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Action {
+    /// Delete the image, the value is the reason logged. No later rule runs.
+    pub discard: Option<String>,
+    /// With `discard`, also block the image's hash so it is rejected before
+    /// tagging if it is imported again.
+    pub block: bool,
+    pub rating: Option<Rating>,
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+    /// Only show the image to admins.
+    pub private: bool,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut effects = Vec::new();
+        if let Some(reason) = &self.discard {
+            let permanently = if self.block { " permanently" } else { "" };
+            effects.push(format!("discard{permanently} ({reason})"));
+        }
+        if let Some(rating) = &self.rating {
+            effects.push(format!("set rating {rating:?}"));
+        }
+        if !self.add_tags.is_empty() {
+            effects.push(format!("add tags {}", self.add_tags.join(", ")));
+        }
+        if !self.remove_tags.is_empty() {
+            effects.push(format!("remove tags {}", self.remove_tags.join(", ")));
+        }
+        if self.private {
+            effects.push("mark private".to_string());
+        }
+        if effects.is_empty() {
+            write!(f, "nothing")
+        } else {
+            write!(f, "{}", effects.join("; "))
+        }
+    }
+}
+
+/// What rules can look at besides the tags.
+pub struct ImageFacts<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub source: Option<&'a str>,
+    pub artist: Option<&'a str>,
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub discard: Option<String>,
+    pub block: bool,
+    pub private: bool,
+}
+
+impl Condition {
+    fn matches(&self, tags: &Tags, facts: &ImageFacts) -> bool {
+        let general = tags.general_tags.as_deref().unwrap_or_default();
+        let characters = tags.character_tags.as_deref().unwrap_or_default();
+        let has = |tag: &String| general.contains(tag);
+        let one_of = |values: &[String], value: Option<&str>| {
+            values.is_empty() || value.is_some_and(|x| values.iter().any(|v| v == x))
+        };
+        let aspect_ratio = facts.height as f32 / facts.width.max(1) as f32;
+
+        (self.tags_any.is_empty() || self.tags_any.iter().any(has))
+            && self.tags_all.iter().all(has)
+            && !self.tags_none.iter().any(has)
+            && (self.characters_any.is_empty()
+                || self.characters_any.iter().any(|x| characters.contains(x)))
+            && (self.rating.is_empty() || self.rating.contains(&tags.rating))
+            && one_of(&self.source, facts.source)
+            && one_of(&self.artist, facts.artist)
+            && self.min_width.is_none_or(|x| facts.width >= x)
+            && self.max_width.is_none_or(|x| facts.width <= x)
+            && self.min_height.is_none_or(|x| facts.height >= x)
+            && self.max_height.is_none_or(|x| facts.height <= x)
+            && self.min_aspect_ratio.is_none_or(|x| aspect_ratio >= x)
+            && self.max_aspect_ratio.is_none_or(|x| aspect_ratio <= x)
+    }
+}
+
+/// Runs `rules` in file order, each seeing the changes of the ones before, and
+/// applies them to `tags`. Every matching rule is logged; with `dry_run` the
+/// caller is expected to throw `tags` and the outcome away.
+pub fn evaluate(rules: &[Rule], tags: &mut Tags, facts: &ImageFacts, dry_run: bool) -> Outcome {
+    let mut outcome = Outcome::default();
+    for rule in rules {
+        if !rule.when.matches(tags, facts) {
+            continue;
+        }
+        info!(rule = %rule.name, action = %rule.then, dry_run, "Ingest rule matched");
+
+        let action = &rule.then;
+        if let Some(reason) = &action.discard {
+            outcome.discard = Some(reason.clone());
+            outcome.block = action.block;
+            break;
+        }
+        if let Some(rating) = &action.rating {
+            tags.rating = rating.clone();
+        }
+        let general = tags.general_tags.get_or_insert_with(Vec::new);
+        general.retain(|x| !action.remove_tags.contains(x));
+        for tag in &action.add_tags {
+            if !general.contains(tag) {
+                general.push(tag.clone());
+            }
+        }
+        outcome.private |= action.private;
+    }
+    outcome
+}
+
+#[derive(Deserialize)]
+struct File {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    modified: Option<SystemTime>,
+    rules: Arc<Vec<Rule>>,
+}
+
+/// The rules file, reloaded whenever its modification time changes. A file that
+/// fails to parse is reported and the previous rules stay in effect.
+#[derive(Debug)]
+pub struct RulesFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl RulesFile {
+    pub fn new(path: PathBuf) -> Self {
+        RulesFile {
+            path,
+            loaded: Mutex::new(Loaded {
+                modified: None,
+                rules: Arc::new(Vec::new()),
+            }),
+        }
+    }
+
+    fn current(&self) -> Arc<Vec<Rule>> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|x| x.modified())
+            .ok();
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.modified != modified {
+            loaded.modified = modified;
+            match modified.map(|_| self.load()).transpose() {
+                Ok(rules) => {
+                    let rules = rules.unwrap_or_default();
+                    info!(rules = rules.len(), path = %self.path.display(), "Loaded ingest rules");
+                    loaded.rules = Arc::new(rules);
+                }
+                Err(e) => error!(error = ?e, "Invalid rules file, keeping the previous rules"),
+            }
+        }
+        loaded.rules.clone()
+    }
+
+    fn load(&self) -> Result<Vec<Rule>> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let file: File = toml::from_str(&contents)
+            .with_context(|| format!("Invalid {}", self.path.display()))?;
+        Ok(file.rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rules: &str) -> Vec<Rule> {
+        toml::from_str::<File>(rules).unwrap().rule
+    }
+
+    fn tags(rating: Rating, general: &[&str]) -> Tags {
+        Tags {
+            rating,
+            character_tags: Some(vec!["hatsune_miku".to_string()]),
+            general_tags: Some(general.iter().map(|x| x.to_string()).collect()),
+        }
+    }
+
+    fn facts(width: u32, height: u32) -> ImageFacts<'static> {
+        ImageFacts {
+            width,
+            height,
+            source: Some("pixiv"),
+            artist: None,
+        }
+    }
+
+    #[test]
+    fn matching_rules_edit_tags_in_order() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            name = "retag"
+            when = { tags_any = ["sketch", "lineart"] }
+            then = { add_tags = ["monochrome"], remove_tags = ["sketch"] }
+
+            [[rule]]
+            name = "sees previous changes"
+            when = { tags_all = ["monochrome"], source = ["pixiv"] }
+            then = { rating = "general", private = true }
+            "#,
+        );
+        let mut tags = tags(Rating::Sensitive, &["sketch", "smile"]);
+        let outcome = evaluate(&rules, &mut tags, &facts(100, 100), false);
+
+        assert_eq!(tags.general_tags.unwrap(), ["smile", "monochrome"]);
+        assert_eq!(tags.rating, Rating::General);
+        assert!(outcome.private);
+        assert!(outcome.discard.is_none());
+    }
+
+    #[test]
+    fn non_matching_rules_change_nothing() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            name = "explicit only"
+            when = { rating = ["explicit"], tags_none = ["smile"] }
+            then = { add_tags = ["nsfw"] }
+
+            [[rule]]
+            name = "other artist"
+            when = { artist = ["someone"] }
+            then = { private = true }
+            "#,
+        );
+        let mut tags = tags(Rating::Explicit, &["smile"]);
+        let outcome = evaluate(&rules, &mut tags, &facts(100, 100), false);
+
+        assert_eq!(tags.general_tags.unwrap(), ["smile"]);
+        assert!(!outcome.private);
+    }
+
+    #[test]
+    fn discard_stops_later_rules() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            name = "too small"
+            when = { max_width = 200, max_height = 200 }
+            then = { discard = "too small", block = true }
+
+            [[rule]]
+            name = "everything"
+            then = { add_tags = ["seen"] }
+            "#,
+        );
+        let mut small = tags(Rating::General, &[]);
+        let outcome = evaluate(&rules, &mut small, &facts(150, 150), false);
+        assert_eq!(outcome.discard.as_deref(), Some("too small"));
+        assert!(outcome.block);
+        assert!(small.general_tags.unwrap().is_empty());
+
+        let mut tall = tags(Rating::General, &[]);
+        let outcome = evaluate(&rules, &mut tall, &facts(150, 300), false);
+        assert!(outcome.discard.is_none());
+        assert_eq!(tall.general_tags.unwrap(), ["seen"]);
+    }
+
+    #[test]
+    fn aspect_ratio_is_height_over_width() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            name = "strip"
+            when = { min_aspect_ratio = 4.0, characters_any = ["hatsune_miku"] }
+            then = { add_tags = ["long_image"] }
+            "#,
+        );
+        let mut strip = tags(Rating::General, &[]);
+        evaluate(&rules, &mut strip, &facts(100, 500), false);
+        assert_eq!(strip.general_tags.unwrap(), ["long_image"]);
+
+        let mut wide = tags(Rating::General, &[]);
+        evaluate(&rules, &mut wide, &facts(500, 100), false);
+        assert!(wide.general_tags.unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let file = r#"
+            [[rule]]
+            name = "typo"
+            when = { tag_any = ["sketch"] }
+            then = { private = true }
+        "#;
+        assert!(toml::from_str::<File>(file).is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    os::unix::fs::MetadataExt,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use tokio::{task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    audit,
+    database::{Database, SqlDatabase},
+    image_path::DISCARD_PATH,
+    processor,
+};
+
+/// How often schedules are checked, so a task starts at most this late.
+const TICK: Duration = Duration::from_secs(60);
+/// Longest a `regenerate_thumbnails` run works through its backlog.
+const REGENERATE_MINUTES: u32 = 30;
+
+/// Recurring maintenance, each run on one worker at a time and recorded in
+/// `scheduled_task_run`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Task {
+    /// Delete files in the discard directory older than the retention.
+    PurgeDiscarded,
+    /// Import failed jobs again that didn't fail because of the file itself.
+    RetryFailedJobs,
+    /// Re-render images whose thumbnails were made with other settings, rate
+    /// limited. Their old thumbnails are served until replaced.
+    RegenerateThumbnails,
+    /// Update planner statistics and count images and jobs.
+    RefreshStatistics,
+    /// Report-only storage audit, published as the "audit" service status.
+    IntegrityCheck,
+}
+
+impl Task {
+    pub const ALL: [Task; 5] = [
+        Task::PurgeDiscarded,
+        Task::RetryFailedJobs,
+        Task::RegenerateThumbnails,
+        Task::RefreshStatistics,
+        Task::IntegrityCheck,
+    ];
+
+    /// Five field cron expression used unless overridden.
+    pub fn default_schedule(&self) -> &'static str {
+        match self {
+            Task::PurgeDiscarded => "0 4 * * *",
+            Task::RetryFailedJobs => "*/30 * * * *",
+            Task::RegenerateThumbnails => "*/10 * * * *",
+            Task::RefreshStatistics => "30 4 * * *",
+            Task::IntegrityCheck => "0 3 * * *",
+        }
+    }
+
+    /// `SCHEDULE_` followed by the task name in upper case.
+    pub fn env_var(&self) -> String {
+        format!("SCHEDULE_{}", self.to_string().to_uppercase())
+    }
+
+    /// Runs the task, returning a summary of what it did.
+    async fn run(&self, database: &impl Database) -> Result<String> {
+        match self {
+            Task::PurgeDiscarded => purge_discarded(database.config().discard_retention_days).await,
+            Task::RetryFailedJobs => retry_failed_jobs(database).await,
+            Task::RegenerateThumbnails => regenerate_thumbnails(database).await,
+            Task::RefreshStatistics => Ok(database.refresh_statistics().await?.to_string()),
+            Task::IntegrityCheck => integrity_check(database).await,
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::PurgeDiscarded => write!(f, "purge_discarded"),
+            Task::RetryFailedJobs => write!(f, "retry_failed_jobs"),
+            Task::RegenerateThumbnails => write!(f, "regenerate_thumbnails"),
+            Task::RefreshStatistics => write!(f, "refresh_statistics"),
+            Task::IntegrityCheck => write!(f, "integrity_check"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduledTask {
+    pub task: Task,
+    pub schedule: Schedule,
+}
+
+/// Parses a standard five field cron expression (minute, hour, day of month,
+/// month, day of week), evaluated in UTC. `off` disables the task.
+pub fn parse_schedule(task: Task, expression: &str) -> Result<Option<ScheduledTask>> {
+    let expression = expression.trim();
+    if expression == "off" {
+        return Ok(None);
+    }
+    // The cron crate also takes seconds, as the first field.
+    let schedule = Schedule::from_str(&format!("0 {expression}"))
+        .map_err(|e| anyhow!("Invalid schedule for {task}: {e}"))?;
+    Ok(Some(ScheduledTask { task, schedule }))
+}
+
+/// Image and job counts, the summary of `refresh_statistics`.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    pub images: u64,
+    pub untagged: u64,
+    pub private: u64,
+    pub broken: u64,
+    pub pending_jobs: u64,
+    pub failed_jobs: u64,
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} images, {} untagged, {} private, {} broken, {} pending jobs, {} failed jobs",
+            self.images,
+            self.untagged,
+            self.private,
+            self.broken,
+            self.pending_jobs,
+            self.failed_jobs
+        )
+    }
+}
+
+/// Starts each task once its schedule comes due, until shutdown. Whether a task
+/// is due is decided by its last recorded run, so with several workers it still
+/// runs once per occurrence, and one missed while every worker was down runs as
+/// soon as one is back. Each task runs on its own, so a long one doesn't hold up
+/// the others; one still running isn't started again.
+pub async fn run(database: SqlDatabase, shutdown: CancellationToken) {
+    // Tasks that never ran wait for their first occurrence after startup.
+    let started = Utc::now();
+    let mut running: HashMap<Task, JoinHandle<()>> = HashMap::new();
+    while !shutdown.is_cancelled() {
+        running.retain(|_, handle| !handle.is_finished());
+        for scheduled in &database.config().schedules {
+            if shutdown.is_cancelled() {
+                break;
+            }
+            let task = scheduled.task;
+            if running.contains_key(&task) {
+                continue;
+            }
+            let (database, scheduled, shutdown) =
+                (database.clone(), scheduled.clone(), shutdown.clone());
+            let handle = tokio::spawn(async move {
+                if let Err(e) = run_if_due(&database, &scheduled, started, &shutdown).await {
+                    warn!(task = %task, error = %e, "Could not run scheduled task");
+                }
+            });
+            running.insert(task, handle);
+        }
+        tokio::select! {
+            _ = sleep(TICK) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+    // Interrupted tasks still record their run.
+    for (_, handle) in running {
+        let _ = handle.await;
+    }
+}
+
+async fn run_if_due(
+    database: &impl Database,
+    scheduled: &ScheduledTask,
+    started: DateTime<Utc>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let name = scheduled.task.to_string();
+    let Some(_lock) = database.try_exclusive(&format!("task:{name}")).await? else {
+        return Ok(());
+    };
+    let last = database
+        .last_task_run(&name)
+        .await?
+        .and_then(|x| DateTime::from_timestamp(x, 0))
+        .unwrap_or(started);
+    if scheduled
+        .schedule
+        .after(&last)
+        .next()
+        .is_none_or(|due| due > Utc::now())
+    {
+        return Ok(());
+    }
+
+    let run = database.start_task_run(&name).await?;
+    info!(task = name, "Running scheduled task");
+    let start = Instant::now();
+    let result = tokio::select! {
+        result = scheduled.task.run(database) => result,
+        _ = shutdown.cancelled() => Err(anyhow!("Interrupted by shutdown")),
+    };
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+    let (outcome, detail) = match result {
+        Ok(detail) => {
+            info!(task = name, elapsed_ms, detail, "Scheduled task finished");
+            ("ok", detail)
+        }
+        Err(e) => {
+            error!(task = name, elapsed_ms, error = ?e, "Scheduled task failed");
+            ("failed", format!("{e:#}"))
+        }
+    };
+    database.finish_task_run(run, outcome, &detail).await
+}
+
+/// Deletes files that have been in the discard directory for more than
+/// `retention_days`. The change time is when a file was moved there, while the
+/// modification time is still that of the import.
+async fn purge_discarded(retention_days: u64) -> Result<String> {
+    let directory = DISCARD_PATH.get().unwrap().clone();
+    tokio::task::spawn_blocking(move || {
+        let cutoff = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64
+            - (retention_days * 86400) as i64;
+        let (mut files, mut bytes) = (0, 0);
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || metadata.ctime() >= cutoff {
+                continue;
+            }
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => {
+                    files += 1;
+                    bytes += metadata.len();
+                }
+                Err(e) => warn!(path = %entry.path().display(), error = %e, "Could not delete discarded file"),
+            }
+        }
+        Ok(format!("Deleted {files} files, {} MiB", bytes >> 20))
+    })
+    .await?
+}
+
+/// Moves the files of retryable failed jobs back to where they were imported
+/// from and queues them again, up to `JOB_RETRY_LIMIT` times per job.
+async fn retry_failed_jobs(database: &impl Database) -> Result<String> {
+    let (mut retried, mut abandoned) = (0, 0);
+    for job in database
+        .get_retryable_jobs(database.config().job_retry_limit)
+        .await?
+    {
+        let in_place = tokio::fs::try_exists(&job.path).await?;
+        let discarded = match &job.discarded_path {
+            Some(path) => tokio::fs::try_exists(path).await?,
+            None => false,
+        };
+        if discarded && !in_place {
+            if let Some(parent) = job.path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(job.discarded_path.as_deref().unwrap(), &job.path).await?;
+        } else if !in_place {
+            // Purged or removed by hand.
+            database.abandon_job(job.id).await?;
+            abandoned += 1;
+            continue;
+        }
+        if database.retry_job(job.id).await? {
+            retried += 1;
+        }
+    }
+    Ok(format!("Retried {retried} jobs, {abandoned} files gone"))
+}
+
+/// Re-renders stale thumbnails one at a time, at most `THUMBNAIL_REFRESH_PER_MINUTE`
+/// a minute so a settings change doesn't starve ingestion. Each run does at most
+/// `REGENERATE_MINUTES` worth; later runs pick up the rest of a long backlog.
+async fn regenerate_thumbnails(database: &impl Database) -> Result<String> {
+    let per_minute = database.config().thumbnail_refresh_per_minute.max(1);
+    let mut stale = database.get_stale_thumbnail_images().await?;
+    let remaining = stale.len().saturating_sub((per_minute * REGENERATE_MINUTES) as usize);
+    stale.truncate((per_minute * REGENERATE_MINUTES) as usize);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(60) / per_minute);
+    let mut failed = 0;
+    for &id in &stale {
+        interval.tick().await;
+        if let Err(e) = processor::thumbnail_image(database, id).await {
+            warn!(image_id = id, error = %e, "Could not regenerate thumbnails");
+            failed += 1;
+        }
+    }
+    Ok(format!(
+        "Regenerated thumbnails of {} images, {failed} failed, {remaining} left",
+        stale.len() - failed
+    ))
+}
+
+/// Fails the run if the audit found problems, so they show as the task's outcome.
+async fn integrity_check(database: &impl Database) -> Result<String> {
+    let report = audit::run(database, audit::AuditOptions::default()).await?;
+    let state = if report.is_clean() { "ok" } else { "problems" };
+    if let Err(e) = database.set_status("audit", state, &report.summary()).await {
+        warn!(error = %e, "Could not write audit status");
+    }
+    if report.is_clean() {
+        Ok(report.summary())
+    } else {
+        warn!("{report}");
+        Err(anyhow!(report.summary()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn next_after(expression: &str, after: DateTime<Utc>) -> DateTime<Utc> {
+        let scheduled = parse_schedule(Task::RefreshStatistics, expression)
+            .unwrap()
+            .unwrap();
+        scheduled.schedule.after(&after).next().unwrap()
+    }
+
+    #[test]
+    fn default_schedules_parse() {
+        for task in Task::ALL {
+            assert!(
+                parse_schedule(task, task.default_schedule()).unwrap().is_some(),
+                "{task}"
+            );
+        }
+    }
+
+    #[test]
+    fn five_fields_are_minute_to_weekday() {
+        let start = Utc.with_ymd_and_hms(2025, 9, 10, 12, 7, 30).unwrap();
+        assert_eq!(
+            next_after("30 4 * * *", start),
+            Utc.with_ymd_and_hms(2025, 9, 11, 4, 30, 0).unwrap()
+        );
+        assert_eq!(
+            next_after("*/10 * * * *", start),
+            Utc.with_ymd_and_hms(2025, 9, 10, 12, 10, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn off_disables_the_task() {
+        assert!(parse_schedule(Task::PurgeDiscarded, "off").unwrap().is_none());
+        assert!(parse_schedule(Task::PurgeDiscarded, " off\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        let error = parse_schedule(Task::IntegrityCheck, "every day").unwrap_err();
+        assert!(error.to_string().contains("integrity_check"), "{error}");
+        assert!(parse_schedule(Task::IntegrityCheck, "61 * * * *").is_err());
+    }
+
+    #[test]
+    fn env_var_names() {
+        assert_eq!(Task::PurgeDiscarded.env_var(), "SCHEDULE_PURGE_DISCARDED");
+        assert_eq!(Task::IntegrityCheck.env_var(), "SCHEDULE_INTEGRITY_CHECK");
+    }
+}
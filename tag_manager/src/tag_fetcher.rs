@@ -1,38 +1,304 @@
-use std::{error::Error, fmt, io::Cursor, sync::OnceLock};
+use std::{
+    error::Error,
+    fmt,
+    io::Cursor,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
 
+use futures::future::join_all;
 use image::{DynamicImage, ImageOutputFormat};
-use reqwest::multipart;
+use metrics::counter;
+use reqwest::{Client, Response, multipart};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Instant, timeout_at},
+};
+use tracing::warn;
+
+use crate::{circuit_breaker, decode::Decoded, telemetry};
+
+/// Longest side of the images sent to the tag service. Its models look at a few
+/// hundred pixels, so anything larger only costs memory and upload time.
+const TAG_INPUT_SIZE: u32 = 1024;
 
 pub static TAGSERVICE_URL: OnceLock<String> = OnceLock::new();
+static BATCHER: OnceLock<mpsc::Sender<TagRequest>> = OnceLock::new();
+static CAPABILITIES: RwLock<Option<Capabilities>> = RwLock::new(None);
+
+type TagRequest = (Vec<u8>, oneshot::Sender<Result<Tags, ImageFetcherError>>);
+
+/// Starts the background task that groups `fetch_tags` calls into batch requests.
+/// A batch is sent once `batch_size` images are queued or `max_wait` has passed
+/// since the first image of the batch arrived.
+pub fn start_batcher(batch_size: usize, max_wait: Duration) {
+    let (sender, receiver) = mpsc::channel(batch_size.max(1) * 4);
+    BATCHER.set(sender).unwrap();
+    tokio::spawn(run_batcher(receiver, batch_size.max(1), max_wait));
+}
+
+/// A copy of `decoded` no larger than the tag service needs. The full size pixels
+/// and their share of the memory budget are released once it is made.
+pub async fn downscale(decoded: Decoded) -> anyhow::Result<DynamicImage> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let image = &decoded.image;
+        if image.width().max(image.height()) > TAG_INPUT_SIZE {
+            image.thumbnail(TAG_INPUT_SIZE, TAG_INPUT_SIZE)
+        } else {
+            DynamicImage::clone(image)
+        }
+    })
+    .await?)
+}
 
 pub async fn fetch_tags(image: &DynamicImage) -> Result<Tags, ImageFetcherError> {
+    let result = request_tags(image).await;
+    if result.is_err() {
+        counter!(telemetry::TAGGER_ERRORS).increment(1);
+    }
+    result
+}
+
+async fn request_tags(image: &DynamicImage) -> Result<Tags, ImageFetcherError> {
+    let buffer = encode_png(image)?;
+
+    let Some(batcher) = BATCHER.get() else {
+        return fetch_single(&Client::new(), buffer).await;
+    };
+
+    let (sender, receiver) = oneshot::channel();
+    batcher
+        .send((buffer, sender))
+        .await
+        .map_err(|_| ImageFetcherError("tag batcher is not running".into()))?;
+
+    receiver.await.map_err(ImageFetcherError::new)?
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, ImageFetcherError> {
     let mut buffer = Vec::new();
 
     image
         .write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png)
-        .map_err(|e| ImageFetcherError(Box::new(e)))?;
+        .map_err(ImageFetcherError::new)?;
+
+    Ok(buffer)
+}
+
+/// Succeeds once the tag service reports that its model is loaded.
+pub async fn check_health() -> Result<(), ImageFetcherError> {
+    Client::new()
+        .get(format!("{}/health", tagservice_url()))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .map_err(ImageFetcherError::new)?;
+
+    Ok(())
+}
+
+/// Feeds the outcome of a request into the tagger circuit breaker. Only transport
+/// failures and server errors count against the service; a bad image does not.
+fn record_availability(
+    response: Result<Response, reqwest::Error>,
+) -> Result<Response, ImageFetcherError> {
+    let breaker = circuit_breaker::TAGGER.get();
+    match response.and_then(Response::error_for_status) {
+        Ok(response) => {
+            if let Some(breaker) = breaker {
+                breaker.record_success();
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            if let Some(breaker) = breaker
+                && e.status().is_none_or(|status| status.is_server_error())
+            {
+                breaker.record_failure();
+            }
+            Err(ImageFetcherError::new(e))
+        }
+    }
+}
+
+fn tagservice_url() -> &'static str {
+    TAGSERVICE_URL
+        .get()
+        .map_or("http://127.0.0.1:8000", |x| x.trim_end_matches('/'))
+}
+
+async fn run_batcher(mut receiver: mpsc::Receiver<TagRequest>, batch_size: usize, max_wait: Duration) {
+    let client = Client::new();
+
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + max_wait;
+        while batch.len() < batch_size {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(request)) => batch.push(request),
+                _ => break,
+            }
+        }
+
+        match capabilities(&client).await {
+            Some(Capabilities {
+                batch: true,
+                max_batch_size,
+                ..
+            }) => {
+                let chunk_size = max_batch_size.unwrap_or(batch_size).max(1);
+                let mut batch = batch.into_iter();
+                loop {
+                    let chunk: Vec<_> = batch.by_ref().take(chunk_size).collect();
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    send_batch(&client, chunk).await;
+                }
+            }
+            _ => send_singles(&client, batch).await,
+        }
+    }
+}
+
+/// What the tag service advertises on `/info`. Services without that endpoint
+/// are treated as single-image only, with an unknown model.
+#[derive(serde::Deserialize, Default, Clone)]
+struct Capabilities {
+    #[serde(default)]
+    batch: bool,
+    max_batch_size: Option<usize>,
+    model: Option<String>,
+}
 
+async fn capabilities(client: &Client) -> Option<Capabilities> {
+    let cached = CAPABILITIES.read().unwrap().clone();
+    if cached.is_some() {
+        return cached;
+    }
+
+    match fetch_capabilities(client).await {
+        Ok(capabilities) => {
+            *CAPABILITIES.write().unwrap() = Some(capabilities.clone());
+            Some(capabilities)
+        }
+        Err(e) => {
+            warn!(error = %e, "Could not query tag service capabilities");
+            None
+        }
+    }
+}
+
+/// Drops the cached `/info` answer. Called whenever the tag service comes back
+/// up, since it may have been restarted with a different model.
+pub fn forget_capabilities() {
+    *CAPABILITIES.write().unwrap() = None;
+}
+
+/// The model the tag service currently runs, if it advertises one.
+pub async fn model_version() -> Option<String> {
+    capabilities(&Client::new()).await?.model
+}
+
+async fn fetch_capabilities(client: &Client) -> Result<Capabilities, ImageFetcherError> {
+    let response = client
+        .get(format!("{}/info", tagservice_url()))
+        .send()
+        .await
+        .map_err(ImageFetcherError::new)?;
+
+    if !response.status().is_success() {
+        return Ok(Capabilities::default());
+    }
+
+    response.json().await.map_err(ImageFetcherError::new)
+}
+
+async fn send_singles(client: &Client, batch: Vec<TagRequest>) {
+    join_all(batch.into_iter().map(|(buffer, sender)| async move {
+        let _ = sender.send(fetch_single(client, buffer).await);
+    }))
+    .await;
+}
+
+async fn fetch_single(client: &Client, buffer: Vec<u8>) -> Result<Tags, ImageFetcherError> {
     let part = multipart::Part::bytes(buffer)
         .file_name("image.png")
         .mime_str("image/png")
-        .map_err(|e| ImageFetcherError(Box::new(e)))?;
+        .map_err(ImageFetcherError::new)?;
 
     let form = multipart::Form::new().part("file", part);
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post("http://127.0.0.1:8000/tag/")
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| ImageFetcherError(Box::new(e)))?;
+    let response = record_availability(
+        client
+            .post(format!("{}/tag/", tagservice_url()))
+            .multipart(form)
+            .send()
+            .await,
+    )?;
 
-    let body = response
-        .text()
-        .await
-        .map_err(|e| ImageFetcherError(Box::new(e)))?;
+    let body = response.text().await.map_err(ImageFetcherError::new)?;
+
+    serde_json::from_str(&body).map_err(ImageFetcherError::new)
+}
+
+async fn send_batch(client: &Client, batch: Vec<TagRequest>) {
+    let (buffers, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+
+    match fetch_batch(client, buffers).await {
+        Ok(results) => {
+            for (sender, result) in senders.into_iter().zip(results) {
+                let _ = sender.send(result);
+            }
+        }
+        Err(e) => {
+            let message = e.to_string();
+            for sender in senders {
+                let _ = sender.send(Err(ImageFetcherError(message.clone().into())));
+            }
+        }
+    }
+}
+
+/// Tags all images in one request. The service answers with one entry per
+/// uploaded file, in upload order, so results are matched back by index.
+async fn fetch_batch(
+    client: &Client,
+    buffers: Vec<Vec<u8>>,
+) -> Result<Vec<Result<Tags, ImageFetcherError>>, ImageFetcherError> {
+    let count = buffers.len();
+    let mut form = multipart::Form::new();
+    for (index, buffer) in buffers.into_iter().enumerate() {
+        let part = multipart::Part::bytes(buffer)
+            .file_name(format!("image{index}.png"))
+            .mime_str("image/png")
+            .map_err(ImageFetcherError::new)?;
+        form = form.part("files", part);
+    }
+
+    let response = record_availability(
+        client
+            .post(format!("{}/tag/batch/", tagservice_url()))
+            .multipart(form)
+            .send()
+            .await,
+    )?;
+
+    let body = response.text().await.map_err(ImageFetcherError::new)?;
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(&body).map_err(ImageFetcherError::new)?;
+
+    if values.len() != count {
+        return Err(ImageFetcherError(
+            format!("sent {count} images but got {} results", values.len()).into(),
+        ));
+    }
 
-    serde_json::from_str(&body).map_err(|e| ImageFetcherError(Box::new(e)))
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(ImageFetcherError::new))
+        .collect())
 }
 
 #[derive(Debug)]
@@ -58,14 +324,17 @@ impl ImageFetcherError {
 }
 
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Tags {
     pub rating: Rating,
     pub character_tags: Option<Vec<String>>,
     pub general_tags: Option<Vec<String>>,
 }
 
-#[derive(serde::Deserialize, sqlx::Type, Clone)]
+/// Ordered from least to most explicit.
+#[derive(
+    serde::Deserialize, serde::Serialize, sqlx::Type, Clone, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(type_name = "rating", rename_all = "lowercase")]
 pub enum Rating {
@@ -0,0 +1,80 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::image_path::storage;
+
+pub const FILES_DISCOVERED: &str = "tag_manager_files_discovered_total";
+pub const FILES_PROCESSED: &str = "tag_manager_files_processed_total";
+pub const FILES_DISCARDED: &str = "tag_manager_files_discarded_total";
+pub const STAGE_DURATION: &str = "tag_manager_stage_duration_seconds";
+pub const TAGGER_ERRORS: &str = "tag_manager_tagger_errors_total";
+pub const IMPORT_BACKLOG: &str = "tag_manager_import_backlog";
+pub const STORAGE_BYTES: &str = "tag_manager_storage_bytes";
+pub const DISK_FREE_BYTES: &str = "tag_manager_disk_free_bytes";
+pub const INGEST_PAUSED: &str = "tag_manager_ingest_paused";
+
+/// Tagging a batch on CPU can take several seconds, decoding a small file well
+/// under one, so the buckets span both.
+const STAGE_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Serves `/metrics` in the Prometheus text format on `address`.
+pub fn install(address: SocketAddr) -> Result<()> {
+    PrometheusBuilder::new()
+        .with_http_listener(address)
+        .set_buckets_for_metric(Matcher::Full(STAGE_DURATION.to_string()), STAGE_BUCKETS)?
+        .install()?;
+
+    describe_counter!(FILES_DISCOVERED, "Files found in the import directory, by kind");
+    describe_counter!(FILES_PROCESSED, "Images stored successfully");
+    describe_counter!(FILES_DISCARDED, "Imports moved to the discard directory, by reason");
+    describe_histogram!(STAGE_DURATION, Unit::Seconds, "Time spent per pipeline stage");
+    describe_counter!(TAGGER_ERRORS, "Images the tag service failed to tag");
+    describe_gauge!(IMPORT_BACKLOG, "Files in the import directory not processed yet");
+    describe_gauge!(STORAGE_BYTES, Unit::Bytes, "Size of all stored originals and thumbnails");
+    describe_gauge!(DISK_FREE_BYTES, Unit::Bytes, "Space available on each volume ingestion writes to");
+    describe_gauge!(INGEST_PAUSED, "1 while ingestion is paused for lack of free space");
+    Ok(())
+}
+
+/// Records how long `future` takes as `stage`, whether it succeeds or not, and
+/// logs it as an event of the current span.
+pub async fn timed<T>(stage: &'static str, future: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = future.await;
+    record_stage(stage, start.elapsed());
+    result
+}
+
+pub fn timed_blocking<T>(stage: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record_stage(stage, start.elapsed());
+    result
+}
+
+fn record_stage(stage: &'static str, elapsed: Duration) {
+    histogram!(STAGE_DURATION, "stage" => stage).record(elapsed.as_secs_f64());
+    debug!(stage, elapsed_ms = elapsed.as_millis() as u64, "Stage finished");
+}
+
+/// Measures storage usage on an interval. This walks every stored object, so it
+/// runs far less often than ingestion.
+pub async fn report_storage_usage(interval: Duration) {
+    loop {
+        match storage().usage().await {
+            Ok(bytes) => gauge!(STORAGE_BYTES).set(bytes as f64),
+            Err(e) => warn!(error = ?e, "Could not measure storage usage"),
+        }
+        sleep(interval).await;
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fmt, io::Cursor, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage, imageops::FilterType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl fmt::Display for ThumbnailFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThumbnailFormat::Jpeg => write!(f, "jpeg"),
+            ThumbnailFormat::WebP => write!(f, "webp"),
+            ThumbnailFormat::Avif => write!(f, "avif"),
+        }
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            "webp" => Ok(ThumbnailFormat::WebP),
+            "avif" => Ok(ThumbnailFormat::Avif),
+            other => Err(anyhow!("Unknown thumbnail format: {other}")),
+        }
+    }
+}
+
+/// `Fit` keeps the aspect ratio, `Square` is rendered from the image's crop rectangle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Fit,
+    Square,
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variant::Fit => write!(f, "fit"),
+            Variant::Square => write!(f, "square"),
+        }
+    }
+}
+
+impl FromStr for Variant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fit" => Ok(Variant::Fit),
+            "square" => Ok(Variant::Square),
+            other => Err(anyhow!("Unknown thumbnail variant: {other}")),
+        }
+    }
+}
+
+/// One encoded thumbnail. `size` is the configured bounding box, `width` and
+/// `height` are the actual dimensions after aspect-preserving scaling.
+pub struct Rendition {
+    pub variant: Variant,
+    pub size: u32,
+    pub format: ThumbnailFormat,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Bumped when rendering changes in a way that should replace existing thumbnails.
+const RENDER_VERSION: u32 = 1;
+
+/// The settings thumbnails are rendered with, stored with each image to find
+/// those rendered with different ones.
+pub fn params(
+    sizes: &[u32],
+    formats: &[ThumbnailFormat],
+    quality: u8,
+    background: [u8; 3],
+) -> String {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    let sizes: Vec<String> = sizes.iter().map(|x| x.to_string()).collect();
+    let mut formats: Vec<String> = formats.iter().map(|x| x.to_string()).collect();
+    formats.sort_unstable();
+    formats.dedup();
+    format!(
+        "v{RENDER_VERSION} sizes={} formats={} quality={quality} background={:02x}{:02x}{:02x}",
+        sizes.join(","),
+        formats.join(","),
+        background[0],
+        background[1],
+        background[2]
+    )
+}
+
+/// Renders every size in every format. Sizes are processed from large to small so
+/// each step resizes the previous, already reduced, image instead of the original.
+/// Images are never scaled up.
+pub fn render(
+    image: &DynamicImage,
+    variant: Variant,
+    sizes: &[u32],
+    formats: &[ThumbnailFormat],
+    quality: u8,
+    background: [u8; 3],
+) -> Result<Vec<Rendition>> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.dedup();
+
+    let mut renditions = Vec::with_capacity(sizes.len() * formats.len());
+    let mut source: Option<DynamicImage> = None;
+    for size in sizes {
+        let current = source.as_ref().unwrap_or(image);
+        let resized = if current.width() <= size && current.height() <= size {
+            current.clone()
+        } else {
+            current.resize(size, size, FilterType::Lanczos3)
+        };
+
+        for format in formats {
+            renditions.push(Rendition {
+                variant,
+                size,
+                format: *format,
+                width: resized.width(),
+                height: resized.height(),
+                bytes: encode(&resized, *format, quality, background)?,
+            });
+        }
+        source = Some(resized);
+    }
+
+    Ok(renditions)
+}
+
+/// Compact BlurHash placeholder, computed from a tiny copy of the image. Uses
+/// more horizontal than vertical components for landscape images and vice versa.
+pub fn blurhash(image: &DynamicImage) -> Result<String> {
+    let small = image.resize(32, 32, FilterType::Triangle).to_rgba8();
+    let (components_x, components_y) = if small.width() >= small.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+    blurhash::encode(
+        components_x,
+        components_y,
+        small.width(),
+        small.height(),
+        small.as_raw(),
+    )
+    .map_err(|e| anyhow!("Could not compute blurhash: {e:?}"))
+}
+
+/// Encodes a thumbnail. WebP and AVIF keep transparency; JPEG can't, so
+/// transparent pixels are composited onto `background` instead of turning black.
+pub fn encode(
+    image: &DynamicImage,
+    format: ThumbnailFormat,
+    quality: u8,
+    background: [u8; 3],
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match format {
+        ThumbnailFormat::Jpeg => {
+            flatten(image, background)
+                .write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Jpeg(quality))?;
+        }
+        ThumbnailFormat::WebP if !image.color().has_alpha() => {
+            let rgb = image.to_rgb8();
+            let encoded =
+                webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height()).encode(quality as f32);
+            buffer.extend_from_slice(&encoded);
+        }
+        ThumbnailFormat::WebP => {
+            let rgba = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality as f32);
+            buffer.extend_from_slice(&encoded);
+        }
+        ThumbnailFormat::Avif => {
+            let rgba = image.to_rgba8();
+            let pixels: Vec<ravif::RGBA8> = rgba
+                .pixels()
+                .map(|x| ravif::RGBA8::new(x[0], x[1], x[2], x[3]))
+                .collect();
+            let quality = f32::from(quality.clamp(1, 100));
+            let encoded = ravif::Encoder::new()
+                .with_quality(quality)
+                .with_alpha_quality(quality)
+                .with_speed(6)
+                .with_bit_depth(ravif::BitDepth::Eight)
+                .encode_rgba(ravif::Img::new(
+                    &pixels[..],
+                    rgba.width() as usize,
+                    rgba.height() as usize,
+                ))?;
+            buffer.extend_from_slice(&encoded.avif_file);
+        }
+    }
+    Ok(buffer)
+}
+
+fn flatten(image: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as u32;
+        let blend = |channel: usize| {
+            ((pixel[channel] as u32 * alpha + background[channel] as u32 * (255 - alpha) + 127)
+                / 255) as u8
+        };
+        Rgb([blend(0), blend(1), blend(2)])
+    })
+}